@@ -1,27 +1,71 @@
 
-use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::sync::mpsc::Receiver;
 
-use ::sqpack_blue::{FFXIV, ExFileIdentifier, Index};
 use ::threadpool::ThreadPool;
-use ::sqpack_blue::FFXIVError;
+use ::control::{CancellationToken, PauseHandle};
+use ::source::AudioSource;
 
 pub enum ThreadStatus<T> {
     Continue(T),
     Complete,
     Error(String, usize),
+    /// The worker is waiting for the process to be resumed
+    Paused,
+    Resumed,
 }
 
-fn get_index_from_map_or_insert<'a>(map: &'a mut HashMap<String, Index>, exf: &ExFileIdentifier, ffxiv: &FFXIV) -> Result<&'a Index, FFXIVError> {
-    Ok(map.entry(exf.get_sqpack_base_file_name())
-        .or_insert(ffxiv.get_index(exf)?))
+/// A change in whether the whole pipeline is paused.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PauseEvent {
+    Paused,
+    Resumed,
 }
 
+/// Follows the statuses received from the workers to tell when every running worker is paused,
+/// and when they resume.
+pub struct PauseTracker {
+    thread_count: usize,
+    completed: usize,
+    paused: usize,
+    notified: bool,
+}
+
+impl PauseTracker {
+    pub fn new(thread_count: usize) -> PauseTracker {
+        PauseTracker { thread_count, completed: 0, paused: 0, notified: false }
+    }
+
+    pub fn update<T>(&mut self, status: &ThreadStatus<T>) -> Option<PauseEvent> {
+        match status {
+            ThreadStatus::Paused => self.paused += 1,
+            ThreadStatus::Resumed => self.paused -= 1,
+            ThreadStatus::Complete => self.completed += 1,
+            _ => {},
+        }
+        let all_paused = self.paused > 0 && self.paused == self.thread_count - self.completed;
+        if all_paused && !self.notified {
+            self.notified = true;
+            Some(PauseEvent::Paused)
+        } else if !all_paused && self.notified {
+            self.notified = false;
+            Some(PauseEvent::Resumed)
+        } else {
+            None
+        }
+    }
+}
+
+/// Runs `handler` on the data of every file in `work` across `thread_count` threads. Files are
+/// taken from a shared queue in the order given, so a thread that draws a long file does not hold
+/// up work the others could do. Each thread reads through its own `ScdReader`.
 pub fn async_processor<O: 'static, F: 'static>(thread_count: usize,
-                                      ffxiv: FFXIV,
-                                      work: &Vec<(usize, ExFileIdentifier)>,
+                                      source: Arc<AudioSource>,
+                                      work: &Vec<(usize, String)>,
+                                      cancel: &CancellationToken,
+                                      pause: &PauseHandle,
                                       handler: F)
     -> Receiver<ThreadStatus<O>>
     where O: Send,
@@ -32,28 +76,45 @@ F: Fn(usize, Vec<u8>) -> ThreadStatus<O> + Send + Sync
 
     let (tx, rx) = mpsc::channel();
 
-    let distributed_work = split_work(work, thread_count);
+    assert_ne!(thread_count, 0, "Cannot split work between 0 loads!");
+    let queue = Arc::new(work.clone());
+    let next_work = Arc::new(AtomicUsize::new(0));
 
     let pool = ThreadPool::new(thread_count);
 
-    distributed_work.into_iter().for_each(|each_work| {
+    (0..thread_count).for_each(|_| {
+        let queue = queue.clone();
+        let next_work = next_work.clone();
         let tx_n = tx.clone();
-        let ffxiv = ffxiv.clone();
+        let source = source.clone();
         let data_handler = data_handler.clone();
+        let cancel = cancel.clone();
+        let pause = pause.clone();
         pool.execute(move || {
-            let mut ff_index_files = HashMap::new();
-            each_work.into_iter().for_each(|(index, exf)| {
-                get_index_from_map_or_insert(&mut ff_index_files, &exf, &ffxiv)
-                    .and_then(|ff_index| {
-                        ffxiv.get_raw_data_with_index(&exf, ff_index)
-                    })
+            let mut reader = source.reader();
+            // checked between files, so a cancelled or paused worker finishes the file it is on
+            let proceed = || {
+                if pause.is_paused() && !cancel.is_cancelled() {
+                    tx_n.send(ThreadStatus::Paused).ok();
+                    pause.wait(&cancel);
+                    tx_n.send(ThreadStatus::Resumed).ok();
+                }
+                !cancel.is_cancelled()
+            };
+            let take_work = || if proceed() {
+                queue.get(next_work.fetch_add(1, Ordering::SeqCst))
+            } else {
+                None
+            };
+            while let Some(&(index, ref path)) = take_work() {
+                reader.read_scd(path)
                     .and_then(|data| {
                         Ok(tx_n.send(data_handler(index, data)).ok())
                     })
                     .unwrap_or_else(|e| {
                         tx_n.send(ThreadStatus::Error(e.to_string(), index)).ok()
                     });
-            });
+            }
             tx_n.send(ThreadStatus::Complete).ok();
         });
     });
@@ -61,28 +122,53 @@ F: Fn(usize, Vec<u8>) -> ThreadStatus<O> + Send + Sync
     rx
 }
 
-fn split_work<T>(indices: &Vec<T>, split_count: usize) -> Vec<Vec<T>> where T: Clone {
-    assert_ne!(split_count, 0, "Cannot split work between 0 loads!");
-    let mut output = Vec::with_capacity(split_count as usize);
-    let each = indices.len() / split_count as usize;
-    let extra = indices.len() % split_count as usize;
-    for i in 0..split_count {
-        let start = (i as usize) * each + if (i as usize) < extra {
-            i as usize
-        } else {
-            extra
-        };
-        let end = start + each + if (i as usize) < extra {
-            1
-        } else {
-            0
-        };
-        let bounds = start..end;
-        use std::iter::FromIterator;
-        let inner = Vec::from_iter(indices[bounds].iter().cloned());
-        output.push(inner);
-    };
+#[cfg(test)]
+mod async_data_processor_tests {
+    use super::*;
+    use ::source::MemorySource;
+
+    #[test]
+    fn processes_each_file_once() {
+        let mut source = MemorySource::new();
+        let work = (0..50).map(|index| {
+            let path = format!("music/ffxiv/BGM_{}.scd", index);
+            source.insert_file(&path, vec![index as u8]);
+            (index, path)
+        }).collect::<Vec<_>>();
+        let recv = async_processor(4, Arc::new(source), &work, &CancellationToken::new(), &PauseHandle::new(),
+                                   |index, data| ThreadStatus::Continue((index, data)));
+
+        let mut processed = Vec::new();
+        let mut completed = 0;
+        while completed < 4 {
+            match recv.recv().unwrap() {
+                ThreadStatus::Continue(done) => processed.push(done),
+                ThreadStatus::Complete => completed += 1,
+                _ => panic!("unexpected status"),
+            }
+        }
+        processed.sort();
+        assert_eq!(processed, (0..50).map(|index| (index, vec![index as u8])).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn pause_events_wait_for_every_thread() {
+        let mut tracker = PauseTracker::new(3);
+        let mut update = |status: ThreadStatus<()>| tracker.update(&status);
+        assert_eq!(update(ThreadStatus::Paused), None);
+        assert_eq!(update(ThreadStatus::Continue(())), None);
+        assert_eq!(update(ThreadStatus::Paused), None);
+        assert_eq!(update(ThreadStatus::Paused), Some(PauseEvent::Paused));
 
-    output
+        // the first worker to continue resumes the pipeline
+        assert_eq!(update(ThreadStatus::Resumed), Some(PauseEvent::Resumed));
+        assert_eq!(update(ThreadStatus::Resumed), None);
+        assert_eq!(update(ThreadStatus::Resumed), None);
 
+        // a finished worker no longer holds the pipeline back
+        assert_eq!(update(ThreadStatus::Paused), None);
+        assert_eq!(update(ThreadStatus::Complete), None);
+        assert_eq!(update(ThreadStatus::Complete), Some(PauseEvent::Paused));
+        assert_eq!(update(ThreadStatus::Resumed), Some(PauseEvent::Resumed));
+    }
 }
@@ -0,0 +1,98 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A hidden file next to `path`, unique to this process and call so that threads writing the same
+/// path do not share a temporary file.
+fn temp_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map_or("output".into(), |name| name.to_string_lossy());
+    let unique = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    path.with_file_name(format!(".{}.{}-{}.tmp", file_name, process::id(), unique))
+}
+
+/// A file written under a temporary name in the same directory as its path, and moved there by
+/// `commit` once complete, so the file at the path is never left half written. The temporary file
+/// is removed if this is dropped without being committed, for example when encoding fails.
+pub struct AtomicFile {
+    file: BufWriter<File>,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> io::Result<AtomicFile> {
+        let temp = temp_path(path);
+        OpenOptions::new().create_new(true).write(true).open(&temp)
+            .map(|file| AtomicFile { file: BufWriter::new(file), temp, path: path.to_path_buf(), committed: false })
+    }
+
+    /// Syncs the data to disk and renames the temporary file over the path.
+    pub fn commit(mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for AtomicFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            fs::remove_file(&self.temp).ok();
+        }
+    }
+}
+
+/// Writes `data` to `path` so the file is either fully written or not changed at all. See
+/// `AtomicFile`.
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    AtomicFile::create(path).and_then(|mut file| {
+        file.write_all(data)?;
+        file.commit()
+    })
+}
+
+#[cfg(test)]
+mod atomic_write_tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn replaces_file() {
+        let dir = env::temp_dir().join(format!("azureost-atomic-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.json");
+        fs::write(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // only the output is left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // an uncommitted file leaves nothing behind
+        AtomicFile::create(&dir.join("failed.ogg")).unwrap().write_all(b"partial").unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -38,6 +38,17 @@ pub struct AzureProcessProgress {
     pub is_skip: bool,
 }
 
+/// A structure used in the callback system during threaded processing. This is passed as an
+/// argument to a callback to indicate that a process has been paused or resumed through a
+/// `PauseHandle`.
+#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+pub struct AzureProcessPause {
+    /// The total number of operations in this threaded process.
+    pub total_operations_count: usize,
+    /// The number of operations completed before the pause
+    pub operations_progress: usize,
+}
+
 /// A structure used in the callback system during threaded processing. This is passed as an
 /// argument to a callback to indicate that a process has experienced a nonfatal error and contains
 /// certain data accordingly.
@@ -83,6 +94,14 @@ pub trait AzureCallbacks {
 
     /// This will be called when the threaded operation has completed
     fn process_complete(&self, info: AzureProcessComplete);
+
+    /// This will be called once every worker of a threaded operation has paused. Does nothing
+    /// unless implemented.
+    fn process_paused(&self, _info: AzureProcessPause) {}
+
+    /// This will be called when a paused threaded operation continues. Does nothing unless
+    /// implemented.
+    fn process_resumed(&self, _info: AzureProcessPause) {}
 }
 
 pub struct NoOpCallback;
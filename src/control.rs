@@ -0,0 +1,115 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Stops a running process from another thread. Clones share the same state, so a front-end can
+/// keep a clone (see `AzureOptions::cancellation_token`) and cancel the process it was passed to.
+///
+/// Cancellation is cooperative: worker threads finish the file they are on, including its writes,
+/// and then stop picking up new files. The process then returns `AzureError::Cancelled`.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Requests cancellation. This cannot be undone.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Pauses and resumes a running process from another thread. Clones share the same state, so a
+/// front-end can keep a clone (see `AzureOptions::pause_handle`) and control the process it was
+/// passed to.
+///
+/// Like cancellation, pausing is cooperative: worker threads finish the file they are on and then
+/// wait. `AzureCallbacks::process_paused` is called once every worker is waiting, and
+/// `AzureCallbacks::process_resumed` once they continue. A paused process can still be cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct PauseHandle {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseHandle {
+    pub fn new() -> PauseHandle {
+        PauseHandle::default()
+    }
+
+    pub fn pause(&self) {
+        *self.state.0.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        let (ref paused, ref resumed) = *self.state;
+        *paused.lock().unwrap() = false;
+        resumed.notify_all();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Blocks the calling worker while paused, returning early once `cancel` is cancelled.
+    pub(crate) fn wait(&self, cancel: &CancellationToken) {
+        let (ref paused, ref resumed) = *self.state;
+        let mut is_paused = paused.lock().unwrap();
+        while *is_paused && !cancel.is_cancelled() {
+            // cancellation does not signal the condvar, so poll for it
+            is_paused = resumed.wait_timeout(is_paused, Duration::from_millis(100)).unwrap().0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod control_tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    /// Starts a worker waiting on `pause`, returning a receiver that gets a message once it continues.
+    fn waiting_worker(pause: &PauseHandle, cancel: &CancellationToken) -> mpsc::Receiver<()> {
+        let (tx, rx) = mpsc::channel();
+        let (pause, cancel) = (pause.clone(), cancel.clone());
+        thread::spawn(move || {
+            pause.wait(&cancel);
+            tx.send(()).unwrap();
+        });
+        rx
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (pause, cancel) = (PauseHandle::new(), CancellationToken::new());
+        pause.pause();
+        assert!(pause.is_paused());
+        let continued = waiting_worker(&pause, &cancel);
+        assert!(continued.recv_timeout(Duration::from_millis(300)).is_err());
+
+        pause.resume();
+        assert!(!pause.is_paused());
+        continued.recv_timeout(Duration::from_secs(5)).unwrap();
+        // waiting while not paused returns immediately
+        waiting_worker(&pause, &cancel).recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn cancel_wakes_paused_worker() {
+        let (pause, cancel) = (PauseHandle::new(), CancellationToken::new());
+        pause.pause();
+        let continued = waiting_worker(&pause, &cancel);
+        assert!(continued.recv_timeout(Duration::from_millis(300)).is_err());
+
+        cancel.cancel();
+        continued.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(pause.is_paused());
+    }
+}
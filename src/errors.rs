@@ -7,12 +7,17 @@ pub enum AzureError {
     FFXIVError(FFXIVError),
     FFXIVErrorVec(Vec<FFXIVError>),
     InvalidBGMIndex(Vec<usize>),
+    InvalidScdPaths(Vec<(usize, String)>),
+    UnableToReadSource(String),
     UnableToCreateSaveFile,
     UnableToReadCompareFile,
     ErrorWritingSaveFile,
     ErrorExporting(&'static str),
+    InvalidExportSettings(&'static str),
+    OutputCollision(String),
     ErrorDecoding,
     UnableToSelect,
+    Cancelled,
 }
 
 impl Error for AzureError {}
@@ -25,12 +30,17 @@ impl std::fmt::Display for AzureError {
             FFXIVError(e) => write!(f, "An error occurred while interfacing with FFXIV! {:?}", e),
             FFXIVErrorVec(e) => write!(f, "Several errors occurred while interfacing with FFXIV! {:?}", e),
             InvalidBGMIndex(index) => write!(f, "The requested index was invalid {:?}", index),
+            InvalidScdPaths(paths) => write!(f, "The BGM sheet names invalid SCD paths {:?}", paths),
+            UnableToReadSource(path) => write!(f, "Unable to read {} from the audio source", path),
             UnableToCreateSaveFile => write!(f, "The save file was unable to be created anew."),
             UnableToReadCompareFile => write!(f, "The compare file was unable to be read or parsed."),
             ErrorWritingSaveFile => write!(f, "There was an error writing to the save file."),
             ErrorExporting(s) => write!(f, "An error occurred during the export process. Reason: {}", s),
+            InvalidExportSettings(s) => write!(f, "The export settings are invalid. Reason: {}", s),
+            OutputCollision(path) => write!(f, "Another track was already exported to {}", path),
             ErrorDecoding => write!(f, "An error occurred while attempting to decode the SCD/OggVorbis Samples"),
             UnableToSelect => write!(f, "Unable to process selection from input"),
+            Cancelled => write!(f, "The process was cancelled"),
         }
     }
 }
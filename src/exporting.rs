@@ -1,10 +1,11 @@
-use std::path::{Path, PathBuf};
-use std::fs::{DirBuilder, OpenOptions};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::fs::DirBuilder;
 use ::errors::AzureError;
-
-#[cfg(feature = "lamemp3")]
-extern crate lame;
+use ::atomic_write::{AtomicFile, write_atomic};
+use ::journal::Journal;
+use ::sha1::Digest;
+use ::scd::ScdEntry;
 
 extern crate vorbis;
 extern crate lewton;
@@ -12,160 +13,427 @@ extern crate lewton;
 use self::vorbis::{Encoder, VorbisQuality};
 use self::lewton::inside_ogg::OggStreamReader;
 
+mod flac;
+mod wav;
+mod settings;
+mod options;
+mod sidecar;
+mod tags;
+mod template;
+mod paths;
+mod stream;
+pub(crate) mod msadpcm;
+#[cfg(feature="lamemp3")]
+mod mp3;
+#[cfg(feature="opus")]
+mod opus;
+#[cfg(feature="opus")]
+mod resample;
+
+pub use self::settings::{VorbisPreset, VorbisSettings, LameBitrateMode, LameSettings};
+pub use self::options::{ExportOptions, LoopPolicy, FadeSettings, FadeCurve, FadeLength, OutputNaming, CollisionPolicy, WorkOrder, ChannelLayout, MAX_LOOP_DURATION};
+pub use self::sidecar::ExportSidecar;
+pub use self::template::PathTemplate;
+pub use self::paths::OutputPaths;
+pub(crate) use self::paths::scd_base_path;
+
+use self::tags::TrackTags;
+pub use self::tags::VorbisRetagger;
+use self::stream::{RenderPlan, Rendered, layer_source, probe};
+
+/// Identifies the SCD entry being exported.
+#[derive(Debug, Clone)]
+pub struct ExportSource {
+    /// The SCD file inside the sqpack, e.g. `music/ffxiv/BGM_Field_Gri_01.scd`
+    pub scd_path: String,
+    /// The path, relative to the export directory and without extension, that output names are
+    /// derived from
+    pub base_path: String,
+    /// The row of the BGM sheet the SCD was referenced by
+    pub bgm_index: usize,
+    pub entry_index: usize,
+    pub entry_count: usize,
+    /// The title to tag the track with. The SCD file name is used when not set.
+    pub title: Option<String>,
+    /// The SHA-1 of the SCD file, as recorded in manifests
+    pub sha1: Digest,
+}
+
+/// The state an export of one track shares with the rest of its run.
+pub struct ExportRun<'a> {
+    /// Claims output paths, shared by every track so collisions between them can be detected
+    pub paths: &'a OutputPaths,
+    /// Records finished layers so an interrupted run can be resumed
+    pub journal: Option<&'a Journal>,
+    /// Whether files that already exist may be replaced
+    pub replace_existing: bool,
+}
+
+impl ExportSource {
+    fn title(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            scd_base_path(&self.scd_path).rsplit('/').next().unwrap_or("").to_string()
+        })
+    }
+}
+
 #[derive(Clone)]
 pub enum ExportMode {
     #[cfg(feature="lamemp3")]
-    MP3(PathBuf),
-    OGG(PathBuf),
+    MP3(PathBuf, LameSettings),
+    OGG(PathBuf, VorbisSettings),
+    /// Lossless FLAC of the looped, faded and layer-split audio.
+    FLAC(PathBuf),
+    /// Uncompressed WAV of the layer-split audio, without the loop and fade applied. The loop
+    /// points are instead stored in a RIFF `smpl` chunk so the audio can be looped natively.
+    WAV(PathBuf),
+    /// Ogg Opus at the given bitrate in kilobits per second. The audio is resampled to 48 kHz.
+    #[cfg(feature="opus")]
+    Opus(PathBuf, u32),
+    /// The de-obfuscated Ogg Vorbis stream of each SCD entry, written as is. Nothing is decoded or
+    /// re-encoded, so the original `LoopStart`/`LoopEnd` comments are kept, but multi-layer
+    /// entries are not split. MSADPCM entries cannot be passed through.
+    Passthrough(PathBuf),
 }
 
-#[inline]
-fn get_looped_len(interleaved_len: usize, loop_info: &LoopInfo, num_channels: usize) -> usize {
-    interleaved_len + (loop_info.end - loop_info.start) * num_channels
+/// Where the loop seams and the fade-out landed in a rendered layer, in frames.
+struct RenderInfo {
+    loop_seams: Vec<usize>,
+    fade_start: Option<usize>,
 }
 
 impl ExportMode {
     pub fn get_path(&self) -> &PathBuf {
         match self {
             #[cfg(feature="lamemp3")]
-            ExportMode::MP3(pb) => pb,
-            ExportMode::OGG(pb) => pb,
+            ExportMode::MP3(pb, _) => pb,
+            ExportMode::OGG(pb, _) => pb,
+            ExportMode::FLAC(pb) => pb,
+            ExportMode::WAV(pb) => pb,
+            #[cfg(feature="opus")]
+            ExportMode::Opus(pb, _) => pb,
+            ExportMode::Passthrough(pb) => pb,
         }
     }
 
-    #[cfg(feature="lamemp3")]
-    fn export_mp3(&self, file_name: &str, data: Vec<i16>, sample_rate: u64) -> Result<(), AzureError> {
-        use self::lame::Lame;
-
-        let mut lame = Lame::new().unwrap();
-        // TODO allow changing quality
-        lame.set_quality(3).unwrap();
-        lame.set_channels(2).unwrap();
-        lame.set_sample_rate(sample_rate as u32).unwrap();
-        lame.set_kilobitrate(128).unwrap();
-        lame.init_params().unwrap();
-        let mut out = vec![0u8; (5 * data.len()) / 4 + 7200];
-        let (left, right): (Vec<(usize, i16)>, Vec<(usize, i16)>) = data
-            .into_iter()
-            .enumerate()
-            .partition(|s| s.0 % 2 == 0);
-        let left = left.into_iter().map(|a| a.1).collect::<Vec<i16>>();
-        let right = right.into_iter().map(|a| a.1).collect::<Vec<i16>>();
-        let output = lame.encode(&left, &right, &mut out).unwrap();
-        let out = out.into_iter().take(output).collect::<Vec<u8>>();
-
-        let path = Path::new(self.get_path()).join(Path::new(file_name).with_extension("mp3"));
+    /// Checks the encoder settings carried by this mode.
+    pub fn validate(&self) -> Result<(), AzureError> {
+        match self {
+            #[cfg(feature="lamemp3")]
+            ExportMode::MP3(_, settings) => settings.validate(),
+            ExportMode::OGG(_, settings) => settings.validate(),
+            #[cfg(feature="opus")]
+            ExportMode::Opus(_, kilobitrate) if *kilobitrate < 6 || *kilobitrate > 510 =>
+                Err(AzureError::InvalidExportSettings("Opus bitrate must be between 6 and 510 kbps")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this mode renders the loop and fade into the output audio.
+    fn bakes_loop(&self) -> bool {
+        match self {
+            ExportMode::WAV(_) => false,
+            _ => true,
+        }
+    }
+
+    /// The extension of the audio files written by this mode.
+    fn extension(&self) -> &'static str {
+        match self {
+            #[cfg(feature="lamemp3")]
+            ExportMode::MP3(..) => "mp3",
+            ExportMode::OGG(..) => "ogg",
+            ExportMode::FLAC(_) => "flac",
+            ExportMode::WAV(_) => "wav",
+            #[cfg(feature="opus")]
+            ExportMode::Opus(..) => "opus",
+            ExportMode::Passthrough(_) => "ogg",
+        }
+    }
+
+    fn exists(&self, file_name: &str) -> bool {
+        Path::new(self.get_path()).join(file_name).exists()
+    }
+
+    /// The path of an output file, creating the directories leading to it.
+    fn output_path(&self, file_name: &str) -> Result<PathBuf, AzureError> {
+        let path = Path::new(self.get_path()).join(file_name);
         path.parent()
             .map(|parent| {
                 DirBuilder::new().recursive(true).create(parent)
                     .map_err(|_| AzureError::ErrorExporting("Creating directory for output"))
             })
             .unwrap_or(Ok(()))
-            .and_then(|_| {
-                OpenOptions::new().create(true).write(true).truncate(true).open(path)
-                    .and_then(|mut file| {
-                        file.write_all(&out)
-                    })
-                    .map_err(|_| AzureError::ErrorExporting("Writing File"))
-            })
+            .map(|_| path)
+    }
 
+    fn write_file(&self, file_name: &str, out: &[u8]) -> Result<(), AzureError> {
+        self.output_path(file_name).and_then(|path| {
+            write_atomic(&path, out)
+                .map_err(|_| AzureError::ErrorExporting("Writing File"))
+        })
+    }
 
+    /// Creates an output file to be encoded into. It only replaces the file at its path once
+    /// committed.
+    fn create_file(&self, file_name: &str) -> Result<AtomicFile, AzureError> {
+        self.output_path(file_name).and_then(|path| {
+            AtomicFile::create(&path)
+                .map_err(|_| AzureError::ErrorExporting("Creating File"))
+        })
     }
 
-    fn export_ogg(&self, file_name: &str, data: Vec<i16>, sample_rate: u64) -> Result<(), AzureError> {
-        // TODO allow changing quality
-        Encoder::new(2, sample_rate, VorbisQuality::Midium)
-            .map_err(|_| AzureError::ErrorExporting("Creating Vorbis encoder"))
-            .and_then(|mut encoder| {
-                encoder.encode(&data)
-                    .map_err(|_| AzureError::ErrorExporting("Encoding vorbis"))
-                    .and_then(|out| {
-                        let path = Path::new(self.get_path()).join(Path::new(file_name).with_extension("ogg"));
-                        path.parent()
-                            .map(|parent| {
-                                DirBuilder::new().recursive(true).create(parent)
-                                    .map_err(|_| AzureError::ErrorExporting("Creating directory for output"))
-                            })
-                            .unwrap_or(Ok(()))
-                            .and_then(|_| {
-                                OpenOptions::new().create(true).write(true).truncate(true).open(path)
-                                    .and_then(|mut file| {
-                                        file.write_all(&out)
-                                    })
-                                    .map_err(|_| AzureError::ErrorExporting("Writing File"))
-                            })
-                    })
-            })
+    #[cfg(feature="lamemp3")]
+    fn export_mp3<I>(&self, out: &mut AtomicFile, samples: I, channels: usize, sample_rate: u64, settings: &LameSettings, tags: Option<&TrackTags>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        if let Some(tags) = tags {
+            out.write_all(&tags::id3v2(&tags.comments()))
+                .map_err(|_| AzureError::ErrorExporting("Writing MP3"))?;
+        }
+        mp3::encode(out, samples, channels, sample_rate, settings)
     }
 
-    pub fn export_file(&self, base_path: &str, scd_entry_index: usize, scd_entry_count: usize, data: Vec<u8>) -> Result<(), AzureError> {
-        decode_ogg(data)
-            .and_then(|decoded| {
+    fn export_ogg<I>(&self, out: &mut AtomicFile, samples: I, channels: usize, sample_rate: u64, settings: &VorbisSettings, tags: Option<&TrackTags>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        let quality = match settings.preset() {
+            VorbisPreset::VeryHighQuality => VorbisQuality::VeryHighQuality,
+            VorbisPreset::HighQuality => VorbisQuality::HighQuality,
+            VorbisPreset::Quality => VorbisQuality::Quality,
+            VorbisPreset::Medium => VorbisQuality::Midium,
+            VorbisPreset::Performance => VorbisQuality::Performance,
+            VorbisPreset::HighPerformance => VorbisQuality::HighPerforamnce,
+            VorbisPreset::VeryHighPerformance => VorbisQuality::VeryHighPerformance,
+        };
+        let encode = |samples: I, emit: &mut FnMut(&[u8]) -> Result<(), AzureError>| {
+            Encoder::new(channels as u8, sample_rate, quality)
+                .map_err(|_| AzureError::ErrorExporting("Creating Vorbis encoder"))
+                .and_then(|mut encoder| {
+                    for chunk in samples {
+                        let encoded = encoder.encode(&chunk?)
+                            .map_err(|_| AzureError::ErrorExporting("Encoding vorbis"))?;
+                        emit(&encoded)?;
+                    }
+                    encoder.flush()
+                        .map_err(|_| AzureError::ErrorExporting("Encoding vorbis"))
+                        .and_then(|encoded| emit(&encoded))
+                })
+        };
+        match tags {
+            Some(tags) => {
+                let mut retagger = VorbisRetagger::new(out, tags.comments());
+                encode(samples, &mut |encoded| retagger.write(encoded))
+            },
+            None => encode(samples, &mut |encoded| {
+                out.write_all(encoded).map_err(|_| AzureError::ErrorExporting("Writing File"))
+            }),
+        }
+    }
 
-                // needs to have at least 2 channels
-                if decoded.channels < 2 {
-                    return Err(AzureError::ErrorExporting("Needs at least 2 channels"))
-                }
+    fn export_flac<I>(&self, out: &mut AtomicFile, samples: I, channels: usize, sample_rate: u64, tags: Option<&TrackTags>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        if sample_rate == 0 || sample_rate >= 1 << 20 {
+            return Err(AzureError::ErrorExporting("Sample rate not representable in FLAC"));
+        }
+        let comments = tags.map(|tags| tags.comments()).unwrap_or_default();
+        flac::encode(out, samples, channels, sample_rate as u32, &comments)
+    }
 
-                // needs to be on L/R channels
-                if decoded.channels % 2 != 0 {
-                    return Err(AzureError::ErrorExporting("needs to be Left/Right channels"))
-                }
+    fn export_wav<I>(&self, out: &mut AtomicFile, samples: I, channels: usize, sample_rate: u64, loop_info: Option<LoopInfo>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        // the byte rate in the fmt chunk must fit as well, and the smpl chunk divides by the rate
+        if sample_rate == 0 || sample_rate * channels as u64 * 2 > u32::max_value() as u64 {
+            return Err(AzureError::ErrorExporting("Sample rate not representable in WAV"));
+        }
+        wav::encode(out, samples, channels, sample_rate as u32, loop_info.map(|info| (info.start, info.end)))
+    }
 
-                let layer_count = decoded.channels / 2;
-
-                let mut layer_index = 0usize;
-
-                let mut in_samples = decoded.samples;
-                for _ in (0..decoded.channels).step_by(2) {
-                    let r_c = in_samples.pop().unwrap();
-                    let l_c = in_samples.pop().unwrap();
-                    let lr_channels = vec![l_c, r_c];
-                    let mut interleaved = interleave(lr_channels);
-
-                    let mut samples = if decoded.loop_info.is_some() {
-                        let info = decoded.loop_info.unwrap();
-                        let mut final_samples = Vec::with_capacity(get_looped_len(interleaved.len(), &info, 2));
-                        final_samples.extend(interleaved.drain(0..info.start * 2));
-                        final_samples.extend(interleaved.iter().cloned().take((info.end - info.start) * 2));
-                        let range = 0..((info.end - info.start) * 2);
-                        final_samples.extend(interleaved.drain(range));
-                        final_samples.extend(interleaved);
-                        final_samples
-                    } else {
-                        interleaved
-                    };
-                    fade(&mut samples, decoded.rate, 2);
-
-                    let layer_name = decoded.channels / 2 - layer_index;
-
-                    let mut base_path = String::from(base_path);
-                    let bp_len = base_path.len();
-                    base_path.truncate(bp_len - 4);
-                    let format_str = if layer_count == 1 {
-                        if scd_entry_count == 1 {
-                            format!("{}", base_path)
-                        } else {
-                            format!("{}_entry{}", base_path, scd_entry_index)
-                        }
-                    } else {
-                        if scd_entry_count == 1 {
-                            format!("{}_layer{}", base_path, layer_name)
-                        } else {
-                            format!("{}_entry{}_layer{}", base_path, scd_entry_index, layer_name)
-                        }
-                    };
+    #[cfg(feature="opus")]
+    fn export_opus<I>(&self, out: &mut AtomicFile, samples: I, channels: usize, sample_rate: u64, kilobitrate: u32, tags: Option<&TrackTags>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        let comments = tags.map(|tags| tags.resampled(sample_rate, opus::OPUS_RATE).comments()).unwrap_or_default();
+        opus::encode(out, samples, channels, sample_rate, kilobitrate, &comments)
+    }
 
+    fn export_passthrough(&self, file_name: &str, data: &[u8]) -> Result<(), AzureError> {
+        if !data.starts_with(b"OggS") {
+            return Err(AzureError::ErrorExporting("Passthrough requires an Ogg Vorbis entry"));
+        }
+        self.write_file(file_name, data)
+    }
 
+    /// Encodes one mono or stereo layer with this mode's encoder as its chunks arrive, writing it
+    /// out as it goes.
+    fn export_samples<I>(&self, file_name: &str, samples: I, channels: usize, rate: u64, loop_info: Option<LoopInfo>, tags: Option<&TrackTags>) -> Result<(), AzureError>
+        where I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+        let mut out = self.create_file(file_name)?;
+        match self {
+            #[cfg(feature="lamemp3")]
+            ExportMode::MP3(_, settings) => self.export_mp3(&mut out, samples, channels, rate, settings, tags),
+            ExportMode::OGG(_, settings) => self.export_ogg(&mut out, samples, channels, rate, settings, tags),
+            ExportMode::FLAC(_) => self.export_flac(&mut out, samples, channels, rate, tags),
+            ExportMode::WAV(_) => self.export_wav(&mut out, samples, channels, rate, loop_info),
+            #[cfg(feature="opus")]
+            ExportMode::Opus(_, kilobitrate) => self.export_opus(&mut out, samples, channels, rate, *kilobitrate, tags),
+            ExportMode::Passthrough(_) => Err(AzureError::ErrorExporting("Passthrough entries are not decoded")),
+        }?;
+        out.commit().map_err(|_| AzureError::ErrorExporting("Writing File"))
+    }
+
+    /// The sidecar shares the name of its audio file, with the extension swapped for `.json`.
+    fn sidecar_name(&self, file_name: &str) -> String {
+        let extension = format!(".{}", self.extension());
+        let stem = if file_name.ends_with(extension.as_str()) {
+            &file_name[..file_name.len() - extension.len()]
+        } else {
+            file_name
+        };
+        format!("{}.json", stem)
+    }
 
-                    match self {
-                        #[cfg(feature="lamemp3")]
-                        ExportMode::MP3(_) => self.export_mp3(format_str.as_str(), samples, decoded.rate)?,
-                        ExportMode::OGG(_) => self.export_ogg(format_str.as_str(), samples, decoded.rate)?,
+    fn write_sidecar(&self, file_name: &str, sidecar: &ExportSidecar) -> Result<(), AzureError> {
+        ::serde_json::to_vec_pretty(sidecar)
+            .map_err(|_| AzureError::ErrorExporting("Serializing sidecar"))
+            .and_then(|out| self.write_file(file_name, &out))
+    }
+
+    /// Exports one SCD entry. Files that already exist are left alone unless
+    /// `run.replace_existing` is set, and layers the journal records as done are not exported
+    /// again, nor do they claim paths, as the run reserves their recorded files up front. Returns
+    /// whether any file was written.
+    pub fn export_file(&self, options: &ExportOptions, run: &ExportRun, source: &ExportSource, entry: ScdEntry) -> Result<bool, AzureError> {
+        let layer_done = |layer: usize| {
+            run.journal.map_or(false, |journal| journal.is_layer_done(source.bgm_index, source.entry_index, layer, &source.sha1))
+        };
+        let record_layer = |layer: usize, files: Vec<String>| {
+            run.journal.map_or(Ok(()), |journal| journal.record_layer(source.bgm_index, source.entry_index, layer, source.sha1, files))
+        };
+
+        if let ExportMode::Passthrough(_) = self {
+            let data = match entry {
+                ScdEntry::Ogg(ref data) => data,
+                ScdEntry::MsAdpcm(_) => return Err(AzureError::ErrorExporting("Passthrough requires an Ogg Vorbis entry")),
+            };
+            if layer_done(1) {
+                return Ok(false);
+            }
+            let file_name = match run.paths.claim(&options.path_template.render(source, 1, 1, None, self.extension()), options.on_collision)? {
+                Some(file_name) => file_name,
+                None => return record_layer(1, Vec::new()).map(|_| false),
+            };
+            if !run.replace_existing && self.exists(&file_name) {
+                return record_layer(1, vec![file_name]).map(|_| false);
+            }
+            let sidecar = if options.sidecar {
+                Some(read_ogg_headers(data).map(|(channels, rate, loop_info)| ExportSidecar {
+                    scd_path: source.scd_path.clone(),
+                    bgm_index: source.bgm_index,
+                    entry_index: source.entry_index,
+                    layer: 1,
+                    layer_count: 1,
+                    sample_rate: rate,
+                    channels,
+                    layer_channels: (0..channels).collect(),
+                    loop_start: loop_info.map(|info| info.start),
+                    loop_end: loop_info.map(|info| info.end),
+                    loop_seams: Vec::new(),
+                    fade_start: None,
+                    frames: last_granule_position(data).unwrap_or(0) as usize,
+                })?)
+            } else {
+                None
+            };
+            self.export_passthrough(file_name.as_str(), data)?;
+            return sidecar.map_or(Ok(()), |sidecar| {
+                self.write_sidecar(self.sidecar_name(&file_name).as_str(), &sidecar)
+            }).and_then(|_| record_layer(1, vec![file_name])).map(|_| true);
+        }
+
+        probe(&entry)
+            .and_then(|info| {
+                let layers = options.channel_layout.layers(info.channels);
+                let layer_count = layers.len();
+                let (rate, frames, loop_info) = (info.rate, info.frames, info.loop_info);
+                let mut written = false;
+
+                // layers are exported from the last to the first
+                for layer_name in (1..layer_count + 1).rev() {
+                    if layer_done(layer_name) {
+                        continue;
+                    }
+                    let layer_channels = &layers[layer_name - 1];
+                    let file_name = |part: Option<&str>| {
+                        run.paths.claim(&options.path_template.render(source, layer_name, layer_count, part, self.extension()), options.on_collision)
+                    };
+
+                    let sidecar = |frames: usize, render: RenderInfo| ExportSidecar {
+                        scd_path: source.scd_path.clone(),
+                        bgm_index: source.bgm_index,
+                        entry_index: source.entry_index,
+                        layer: layer_name,
+                        layer_count,
+                        sample_rate: rate,
+                        channels: info.channels,
+                        layer_channels: layer_channels.clone(),
+                        loop_start: loop_info.map(|info| info.start),
+                        loop_end: loop_info.map(|info| info.end),
+                        loop_seams: render.loop_seams,
+                        fade_start: render.fade_start,
+                        frames,
+                    };
+                    let unrendered = || RenderInfo { loop_seams: Vec::new(), fade_start: None };
+                    let track_tags = |loop_info: Option<LoopInfo>, frames: usize| TrackTags {
+                        title: source.title(),
+                        album: tags::album_name(source.scd_path.as_str()),
+                        track_number: source.bgm_index,
+                        entry: if source.entry_count > 1 { Some(source.entry_index) } else { None },
+                        layer: if layer_count > 1 { Some(layer_name) } else { None },
+                        loop_points: loop_info.filter(|info| info.fits(frames)).map(|info| (info.start, info.end)),
                     };
-                    layer_index += 1;
+
+                    let mut outputs = Vec::with_capacity(2);
+                    match loop_info.filter(|info| options.split_intro && info.fits(frames)) {
+                        Some(info) => {
+                            if info.start > 0 {
+                                outputs.push((Some("intro"), RenderPlan::once(0..info.start), None, unrendered()));
+                            }
+                            let body_loop = LoopInfo { start: 0, end: info.end - info.start };
+                            outputs.push((Some("loop"), RenderPlan::once(info.start..info.end), Some(body_loop), unrendered()));
+                        },
+                        None => {
+                            let (plan, render) = if self.bakes_loop() {
+                                RenderPlan::render(frames, loop_info, rate, options)?
+                            } else {
+                                (RenderPlan::once(0..frames), unrendered())
+                            };
+                            outputs.push((None, plan, loop_info, render));
+                        },
+                    }
+
+                    let mut files = Vec::with_capacity(outputs.len());
+                    for (part, plan, loop_info, render) in outputs {
+                        let file_name = match file_name(part)? {
+                            Some(file_name) => file_name,
+                            None => continue,
+                        };
+                        files.push(file_name.clone());
+                        if !run.replace_existing && self.exists(&file_name) {
+                            continue;
+                        }
+                        let frames = plan.frames();
+                        // baked renders keep their loop points in the tags so players can still
+                        // seek to the loop, even though it is already repeated in the audio
+                        let tags = if options.tags { Some(track_tags(loop_info, frames)) } else { None };
+                        let sidecar = sidecar(frames, render);
+                        let layer = layer_source(&entry, layer_channels.clone())?;
+                        self.export_samples(file_name.as_str(), Rendered::new(layer, &plan), layer_channels.len(), rate, loop_info, tags.as_ref())?;
+                        if options.sidecar {
+                            self.write_sidecar(self.sidecar_name(&file_name).as_str(), &sidecar)?;
+                        }
+                        written = true;
+                    }
+                    record_layer(layer_name, files)?;
                 };
-                Ok(())
+                Ok(written)
             })
     }
 }
@@ -176,11 +444,11 @@ struct LoopInfo {
     pub end: usize,
 }
 
-struct DecodedOgg {
-    pub samples: Vec<Vec<i16>>,
-    pub rate: u64,
-    pub channels: usize,
-    pub loop_info: Option<LoopInfo>,
+impl LoopInfo {
+    /// Whether the loop region is non-empty and lies within `frames` frames of audio.
+    fn fits(&self, frames: usize) -> bool {
+        self.start < self.end && self.end <= frames
+    }
 }
 
 fn extract_loop_info(comments: Vec<(String, String)>) -> Option<LoopInfo> {
@@ -213,59 +481,28 @@ fn extract_loop_info(comments: Vec<(String, String)>) -> Option<LoopInfo> {
         })
 }
 
-fn decode_ogg(scd_ogg: Vec<u8>) -> Result<DecodedOgg, AzureError> {
+/// Reads the channel count, sample rate and loop points from the headers of an Ogg Vorbis stream
+/// without decoding any audio.
+fn read_ogg_headers(scd_ogg: &[u8]) -> Result<(usize, u64, Option<LoopInfo>), AzureError> {
     use std::io::Cursor;
     OggStreamReader::new(Cursor::new(scd_ogg))
         .map_err(|_| AzureError::ErrorDecoding)
-        .and_then(|mut osr| {
-            let mut samples = vec![Vec::<i16>::new(); osr.ident_hdr.audio_channels as usize];
-            let mut has_samples: bool = true;
-             while has_samples {
-                 let packet = osr.read_dec_packet().map_err(|_|AzureError::ErrorDecoding)?;
-                 if packet.is_some() {
-                     let vecs = packet.unwrap();
-                     for (i, pkt_samples) in vecs.into_iter().enumerate() {
-                         samples[i].extend(pkt_samples);
-                     }
-                 } else { has_samples = false }
-             }
-            Ok((osr.comment_hdr.comment_list, samples, osr.ident_hdr.audio_sample_rate))
-        })
-        .and_then(|(comment_list, samples, rate)| {
-            Ok(DecodedOgg {
-                channels: samples.len(),
-                samples,
-                rate: rate as u64,
-                loop_info: extract_loop_info(comment_list)
-            })
+        .map(|osr| {
+            (osr.ident_hdr.audio_channels as usize,
+             osr.ident_hdr.audio_sample_rate as u64,
+             extract_loop_info(osr.comment_hdr.comment_list))
         })
 }
 
-/// sample_index: interleaved index
-/// fade_start interleaved index
-/// fade_length raw index
-fn fade_val(sample_index: usize, fade_start: usize, fade_length: usize, channels: usize) -> f32 {
-    if sample_index >= fade_start {
-        let ch_sample_index = sample_index / channels;
-        let ch_fade_start = fade_start / channels;
-        (fade_length + ch_fade_start - ch_sample_index) as f32 / fade_length as f32
-    } else { 1f32 }
-}
-
-fn fade(samp: &mut Vec<i16>, rate: u64, channels: usize) {
-    let len = samp.len();
-    // fade for the last 5% of a song or 30 seconds, whichever is less
-    let fade_length = (rate as usize * 30).min((len as f32 / channels as f32 * 0.05) as usize);
-    let fade_start = len - fade_length * channels;
-    samp.iter_mut().enumerate().for_each(|sample| {
-        (*sample.1) = ((*sample.1 as f32) *
-            fade_val(sample.0, fade_start, fade_length, channels)
-
-        ) as i16;
-    });
+/// Finds the granule position of the last Ogg page, which is the stream's length in frames.
+fn last_granule_position(scd_ogg: &[u8]) -> Option<u64> {
+    (0..scd_ogg.len().saturating_sub(13)).rev()
+        .find(|i| &scd_ogg[*i..*i + 4] == b"OggS")
+        .map(|i| {
+            scd_ogg[i + 6..i + 14].iter().rev().fold(0u64, |acc, byte| (acc << 8) | *byte as u64)
+        })
 }
 
-
 fn interleave<T>(input: Vec<Vec<T>>) -> Vec<T> {
     let capacity = input.len()*input[0].len();
     let mut t = input.into_iter().map(|a| {
@@ -283,4 +520,171 @@ fn interleave<T>(input: Vec<Vec<T>>) -> Vec<T> {
         }
     }
     end
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod exporting_tests {
+    use super::*;
+
+    #[test]
+    fn loop_minimum_duration() {
+        let policy = LoopPolicy::MinimumDuration(10.0);
+        assert_eq!(policy.iterations(12, 4, 1), 1);
+        assert_eq!(policy.iterations(6, 4, 1), 2);
+        assert_eq!(policy.iterations(6, 1, 1), 5);
+        assert_eq!(LoopPolicy::Count(3).iterations(6, 4, 1), 3);
+
+        let validate = |loop_policy| ExportOptions { loop_policy, ..ExportOptions::default() }.validate();
+        assert!(validate(LoopPolicy::MinimumDuration(MAX_LOOP_DURATION)).is_ok());
+        assert!(validate(LoopPolicy::MinimumDuration(MAX_LOOP_DURATION + 1.0)).is_err());
+        assert!(validate(LoopPolicy::MinimumDuration(::std::f32::INFINITY)).is_err());
+        assert!(validate(LoopPolicy::MinimumDuration(0.0)).is_err());
+    }
+
+    #[test]
+    fn probe_fixture() {
+        // long enough to span several pages, which the decoder needs to trim the stream exactly
+        let ogg = ::fixtures::FixtureEntry::new(4, 44100, 60000).looped(10000, 50000).ogg_vorbis().unwrap();
+        let info = probe(&ScdEntry::Ogg(ogg)).unwrap();
+        assert_eq!((info.channels, info.rate, info.frames), (4, 44100, 60000));
+        assert_eq!(info.loop_info.map(|info| (info.start, info.end)), Some((10000, 50000)));
+    }
+
+    /// Exports `entry` as the only entry of `music/ffxiv/BGM_Test.scd` into a fresh directory,
+    /// returning the contents of `files`, given relative to that directory.
+    fn export(mode: fn(PathBuf) -> ExportMode, options: &ExportOptions, entry: ScdEntry, files: &[&str]) -> Result<Vec<Vec<u8>>, AzureError> {
+        use std::{env, fs};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static EXPORTS: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!("azureost-export-{}-{}", ::std::process::id(), EXPORTS.fetch_add(1, Ordering::SeqCst)));
+        let paths = OutputPaths::new();
+        let run = ExportRun { paths: &paths, journal: None, replace_existing: true };
+        let source = ExportSource {
+            scd_path: "music/ffxiv/BGM_Test.scd".into(),
+            base_path: "ffxiv/BGM_Test".into(),
+            bgm_index: 1,
+            entry_index: 0,
+            entry_count: 1,
+            title: None,
+            sha1: ::sha1::Sha1::new().digest(),
+        };
+        let written = mode(dir.clone()).export_file(options, &run, &source, entry).map(|written| {
+            assert!(written);
+            files.iter().map(|file| fs::read(dir.join(file)).unwrap()).collect()
+        });
+        fs::remove_dir_all(&dir).ok();
+        written
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> usize {
+        bytes[at..at + 4].iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as usize)
+    }
+
+    #[test]
+    fn export_layers() {
+        let entry = ::fixtures::FixtureEntry::new(4, 44100, 60000).looped(10000, 50000).entry().unwrap();
+        let options = ExportOptions::default();
+        let files = export(ExportMode::FLAC, &options, entry, &["ffxiv/BGM_Test_layer1.flac", "ffxiv/BGM_Test_layer2.flac"]).unwrap();
+
+        let (plan, _) = RenderPlan::render(60000, Some(LoopInfo { start: 10000, end: 50000 }), 44100, &options).unwrap();
+        for flac in files {
+            let total_samples = flac[22..26].iter().fold(0, |acc, byte| (acc << 8) | *byte as usize);
+            assert_eq!(total_samples, plan.frames());
+        }
+    }
+
+    #[test]
+    fn export_adpcm() {
+        let entry = ::fixtures::FixtureEntry::new(2, 44100, 2440).looped(488, 1952).msadpcm();
+        // parsed from an SCD, so the loop points go through the byte offsets of the entry header
+        let parsed = ::scd::entries(&::fixtures::scd(&[entry]).unwrap()).unwrap().remove(0);
+        let wav = export(ExportMode::WAV, &ExportOptions::default(), parsed, &["ffxiv/BGM_Test.wav"]).unwrap().remove(0);
+
+        assert_eq!(read_u32(&wav, 40), 2440 * 4);
+        // the loop points converted back to frames, with the end inclusive
+        assert_eq!((read_u32(&wav, 44 + 2440 * 4 + 52), read_u32(&wav, 44 + 2440 * 4 + 56)), (488, 1951));
+        let decoded = wav[44..44 + 2440 * 4].chunks(2).map(|sample| (sample[0] as u16 | (sample[1] as u16) << 8) as i16);
+        let error = decoded.zip(entry.samples()).map(|(decoded, sample)| (decoded as i32 - sample as i32).abs()).max();
+        assert!(error.unwrap() < 400, "{:?}", error);
+    }
+
+    #[test]
+    fn split_intro_joins_back() {
+        let entry = ::fixtures::FixtureEntry::new(2, 44100, 60000).looped(10000, 50000).entry().unwrap();
+        let whole = export(ExportMode::WAV, &ExportOptions::default(), entry.clone(), &["ffxiv/BGM_Test.wav"]).unwrap().remove(0);
+        let options = ExportOptions { split_intro: true, ..ExportOptions::default() };
+        let parts = export(ExportMode::WAV, &options, entry.clone(), &["ffxiv/BGM_Test_intro.wav", "ffxiv/BGM_Test_loop.wav"]).unwrap();
+
+        let pcm = |wav: &Vec<u8>| wav[44..44 + read_u32(wav, 40)].to_vec();
+        assert_eq!(pcm(&parts[0]).len(), 10000 * 4);
+        assert_eq!([pcm(&parts[0]), pcm(&parts[1])].concat(), pcm(&whole)[..50000 * 4].to_vec());
+        let flacs = export(ExportMode::FLAC, &options, entry, &["ffxiv/BGM_Test_intro.flac", "ffxiv/BGM_Test_loop.flac"]).unwrap();
+        let total_samples = |flac: &Vec<u8>| flac[22..26].iter().fold(0, |acc, byte| (acc << 8) | *byte as usize);
+        assert_eq!((total_samples(&flacs[0]), total_samples(&flacs[1])), (10000, 40000));
+    }
+
+    #[test]
+    fn wav_rejects_zero_rate() {
+        let mut adpcm = ::fixtures::FixtureEntry::new(2, 22050, 1000).msadpcm_audio();
+        adpcm.rate = 0;
+        assert!(export(ExportMode::WAV, &ExportOptions::default(), ScdEntry::MsAdpcm(adpcm), &[]).is_err());
+    }
+
+    #[test]
+    fn passthrough_samples_are_an_error() {
+        use std::{env, fs};
+        let dir = env::temp_dir().join(format!("azureost-passthrough-{}", ::std::process::id()));
+        let samples = vec![Ok(vec![0i16; 4])].into_iter();
+        let result = ExportMode::Passthrough(dir.clone()).export_samples("BGM.ogg", samples, 2, 44100, None, None);
+        assert!(result.is_err());
+        assert!(!dir.join("BGM.ogg").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn channel_layouts() {
+        assert_eq!(ChannelLayout::Upmix.layers(1), vec![vec![0, 0]]);
+        assert_eq!(ChannelLayout::Upmix.layers(3), vec![vec![0, 1], vec![2, 2]]);
+        assert_eq!(ChannelLayout::KeepMono.layers(3), vec![vec![0, 1], vec![2]]);
+        let map = ChannelLayout::Map(vec![(3, vec![vec![2, 0], vec![1]])].into_iter().collect());
+        assert_eq!(map.layers(3), vec![vec![2, 0], vec![1]]);
+        assert_eq!(map.layers(4), vec![vec![0, 1], vec![2, 3]]);
+
+        let options = |layers: Vec<Vec<usize>>| ExportOptions {
+            channel_layout: ChannelLayout::Map(vec![(2, layers)].into_iter().collect()),
+            ..ExportOptions::default()
+        };
+        assert!(options(vec![vec![1, 0]]).validate().is_ok());
+        assert!(options(vec![vec![0, 2]]).validate().is_err());
+        assert!(options(vec![vec![0, 1, 1]]).validate().is_err());
+        assert!(options(Vec::new()).validate().is_err());
+    }
+
+    #[test]
+    fn export_mono() {
+        let entry = ::fixtures::FixtureEntry::new(1, 22050, 1000).msadpcm();
+        let export_as = |channel_layout: ChannelLayout| {
+            let options = ExportOptions { channel_layout, ..ExportOptions::default() };
+            export(ExportMode::WAV, &options, entry.entry().unwrap(), &["ffxiv/BGM_Test.wav"]).unwrap().remove(0)
+        };
+
+        let mono = export_as(ChannelLayout::KeepMono);
+        assert_eq!((mono[22], read_u32(&mono, 40)), (1, 2000));
+        let upmixed = export_as(ChannelLayout::Upmix);
+        assert_eq!((upmixed[22], read_u32(&upmixed, 40)), (2, 4000));
+        // both sides carry the mono channel
+        assert!(upmixed[44..44 + 4000].chunks(4).all(|frame| frame[..2] == frame[2..]));
+        assert!(upmixed[44..44 + 4000].chunks(4).zip(mono[44..44 + 2000].chunks(2)).all(|(frame, sample)| frame[..2] == *sample));
+    }
+
+    #[test]
+    fn fade_curves() {
+        for curve in &[FadeCurve::Linear, FadeCurve::Exponential, FadeCurve::EqualPower, FadeCurve::Logarithmic] {
+            assert!((curve.gain(0.0) - 1.0).abs() < 1e-6);
+            assert!(curve.gain(1.0).abs() < 1e-6);
+            assert!(curve.gain(0.25) > curve.gain(0.75));
+        }
+        assert!(FadeCurve::Logarithmic.gain(0.5) > FadeCurve::Linear.gain(0.5));
+        assert!(FadeCurve::Exponential.gain(0.5) < FadeCurve::Linear.gain(0.5));
+    }
+}
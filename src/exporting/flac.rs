@@ -0,0 +1,496 @@
+//! A small FLAC encoder for 16-bit interleaved PCM. Only the parts of the format needed to write
+//! a valid stream are implemented: a STREAMINFO block, fixed-size blocks, independent channels,
+//! CONSTANT/VERBATIM/FIXED subframes and partitioned Rice residuals, with the MD5 of the audio.
+
+use std::io::{Seek, SeekFrom, Write};
+use ::errors::AzureError;
+use super::tags::vorbis_comment_body;
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+const MAX_RICE_PARAM: u32 = 14;
+const MAX_PARTITION_ORDER: u32 = 8;
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    acc_bits: u32,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), acc: 0, acc_bits: 0 }
+    }
+
+    /// Writes the lowest `bits` bits of `value`, most significant first. `bits` must be <= 32.
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        self.acc = (self.acc << bits) | (value & ((1u64 << bits) - 1));
+        self.acc_bits += bits;
+        while self.acc_bits >= 8 {
+            self.acc_bits -= 8;
+            self.bytes.push((self.acc >> self.acc_bits) as u8);
+        }
+        self.acc &= (1u64 << self.acc_bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    fn write_unary(&mut self, zeros: u64) {
+        let mut zeros = zeros;
+        while zeros >= 32 {
+            self.write(0, 32);
+            zeros -= 32;
+        }
+        self.write(1, zeros as u32 + 1);
+    }
+
+    fn align(&mut self) {
+        if self.acc_bits > 0 {
+            let pad = 8 - self.acc_bits;
+            self.write(0, pad);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 }
+        })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 }
+        })
+    })
+}
+
+/// Shift amounts of the four MD5 rounds.
+const MD5_SHIFTS: [[u32; 4]; 4] = [[7, 12, 17, 22], [5, 9, 14, 20], [4, 11, 16, 23], [6, 10, 15, 21]];
+
+/// An incremental MD5, as STREAMINFO records one of the audio.
+#[derive(Clone)]
+struct Md5 {
+    state: [u32; 4],
+    /// The sine derived constant of each step
+    k: Vec<u32>,
+    pending: Vec<u8>,
+    len: u64,
+}
+
+impl Default for Md5 {
+    fn default() -> Md5 {
+        Md5 {
+            state: [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476],
+            k: (0..64).map(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32).collect(),
+            pending: Vec::with_capacity(64),
+            len: 0,
+        }
+    }
+}
+
+impl Md5 {
+    fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        self.pending.extend_from_slice(data);
+        let blocks = self.pending.len() / 64;
+        for block in 0..blocks {
+            let (state, k, pending) = (&mut self.state, &self.k, &self.pending);
+            md5_block(state, k, &pending[block * 64..block * 64 + 64]);
+        }
+        self.pending.drain(..blocks * 64);
+    }
+
+    fn digest(&self) -> [u8; 16] {
+        let mut md5 = self.clone();
+        let bit_len = md5.len.wrapping_mul(8);
+        let zeros = (64 + 55 - md5.len % 64) % 64;
+        let mut padding = vec![0x80u8];
+        padding.extend((0..zeros).map(|_| 0u8));
+        padding.extend((0..8).map(|i| (bit_len >> (8 * i)) as u8));
+        md5.update(&padding);
+        let mut digest = [0u8; 16];
+        md5.state.iter().enumerate().for_each(|(i, word)| {
+            (0..4).for_each(|byte| digest[i * 4 + byte] = (word >> (8 * byte)) as u8);
+        });
+        digest
+    }
+}
+
+fn md5_block(state: &mut [u32; 4], k: &[u32], block: &[u8]) {
+    let m = (0..16).map(|i| {
+        block[i * 4] as u32 | (block[i * 4 + 1] as u32) << 8 | (block[i * 4 + 2] as u32) << 16 | (block[i * 4 + 3] as u32) << 24
+    }).collect::<Vec<u32>>();
+    let (mut a, mut b, mut c, mut d) = (state[0], state[1], state[2], state[3]);
+    for i in 0..64 {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let rotated = a.wrapping_add(f).wrapping_add(k[i]).wrapping_add(m[g]).rotate_left(MD5_SHIFTS[i / 16][i % 4]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(rotated);
+    }
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+/// Frame numbers are written with the same variable length coding as UTF-8.
+fn write_utf8_number(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let mut continuation = 1u32;
+    while value >= (1u64 << (5 * continuation + 6)) {
+        continuation += 1;
+    }
+    let lead_bits = 6 - continuation;
+    let lead_mask = !((1u64 << (7 - continuation)) - 1) & 0xFF;
+    writer.write(lead_mask | (value >> (6 * continuation)) & ((1u64 << lead_bits) - 1), 8);
+    for i in (0..continuation).rev() {
+        writer.write(0x80 | ((value >> (6 * i)) & 0x3F), 8);
+    }
+}
+
+#[inline]
+fn fold(residual: i64) -> u64 {
+    if residual >= 0 { (residual as u64) << 1 } else { ((-residual as u64) << 1) - 1 }
+}
+
+fn fixed_residuals(samples: &[i64], order: usize) -> Vec<i64> {
+    (order..samples.len()).map(|i| {
+        let s = |back: usize| samples[i - back];
+        match order {
+            0 => s(0),
+            1 => s(0) - s(1),
+            2 => s(0) - 2 * s(1) + s(2),
+            3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+            _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+        }
+    }).collect()
+}
+
+fn rice_bits(folded: &[u64], param: u32) -> u64 {
+    folded.iter().map(|u| (u >> param) + 1 + param as u64).sum()
+}
+
+/// Finds the cheapest Rice parameter for a single partition, returning it with its cost in bits.
+fn best_rice_param(folded: &[u64]) -> (u32, u64) {
+    let sum: u64 = folded.iter().sum();
+    let mean = if folded.is_empty() { 0 } else { sum / folded.len() as u64 };
+    let estimate = (64 - mean.leading_zeros()).min(MAX_RICE_PARAM);
+    let low = estimate.saturating_sub(1);
+    let high = (estimate + 1).min(MAX_RICE_PARAM);
+    (low..=high)
+        .map(|param| (param, rice_bits(folded, param)))
+        .min_by_key(|&(_, bits)| bits)
+        .unwrap()
+}
+
+/// Chooses the partition order and Rice parameters that minimize the residual size.
+fn plan_residual(residuals: &[i64], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let folded = residuals.iter().map(|r| fold(*r)).collect::<Vec<u64>>();
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        if block_size % partitions != 0 || block_size / partitions <= order {
+            break;
+        }
+        let partition_len = block_size / partitions;
+        let mut params = Vec::with_capacity(partitions);
+        let mut bits = 0u64;
+        let mut start = 0usize;
+        for p in 0..partitions {
+            let len = if p == 0 { partition_len - order } else { partition_len };
+            let (param, cost) = best_rice_param(&folded[start..start + len]);
+            params.push(param);
+            bits += cost + 4;
+            start += len;
+        }
+        if best.as_ref().map_or(true, |b| bits < b.2) {
+            best = Some((partition_order, params, bits));
+        }
+    }
+    best.unwrap()
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i64]) {
+    let block_size = samples.len();
+
+    if samples.iter().all(|s| *s == samples[0]) {
+        writer.write(0b0000_0000, 8);
+        writer.write_signed(samples[0], BITS_PER_SAMPLE);
+        return;
+    }
+
+    let verbatim_bits = block_size as u64 * BITS_PER_SAMPLE as u64;
+    let max_order = 4.min(block_size - 1);
+    let best = (0..=max_order)
+        .map(|order| {
+            let residuals = fixed_residuals(samples, order);
+            let plan = plan_residual(&residuals, block_size, order);
+            let bits = order as u64 * BITS_PER_SAMPLE as u64 + 6 + plan.2;
+            (order, residuals, plan, bits)
+        })
+        .min_by_key(|candidate| candidate.3)
+        .unwrap();
+
+    if best.3 >= verbatim_bits {
+        writer.write(0b0000_0010, 8);
+        samples.iter().for_each(|s| writer.write_signed(*s, BITS_PER_SAMPLE));
+        return;
+    }
+
+    let (order, residuals, (partition_order, params, _), _) = best;
+    writer.write(0b0001_0000 | ((order as u64) << 1), 8);
+    samples.iter().take(order).for_each(|s| writer.write_signed(*s, BITS_PER_SAMPLE));
+    writer.write(0, 2);
+    writer.write(partition_order as u64, 4);
+    let partition_len = block_size >> partition_order;
+    let mut start = 0usize;
+    for (p, param) in params.iter().enumerate() {
+        let len = if p == 0 { partition_len - order } else { partition_len };
+        writer.write(*param as u64, 4);
+        residuals[start..start + len].iter().for_each(|r| {
+            let u = fold(*r);
+            writer.write_unary(u >> param);
+            writer.write(u, *param);
+        });
+        start += len;
+    }
+}
+
+fn write_frame(out: &mut Vec<u8>, frame_number: u64, channels: &[Vec<i64>]) -> usize {
+    let block_size = channels[0].len();
+    let mut writer = BitWriter::new();
+    writer.write(0b11_1111_1111_1110, 14);
+    writer.write(0, 1);
+    writer.write(0, 1);
+    let block_size_code = if block_size == BLOCK_SIZE { 0b1100 } else { 0b0111 };
+    writer.write(block_size_code, 4);
+    writer.write(0b0000, 4);
+    writer.write(channels.len() as u64 - 1, 4);
+    writer.write(0b100, 3);
+    writer.write(0, 1);
+    write_utf8_number(&mut writer, frame_number);
+    if block_size_code == 0b0111 {
+        writer.write(block_size as u64 - 1, 16);
+    }
+    let header_crc = crc8(&writer.bytes);
+    writer.write(header_crc as u64, 8);
+
+    channels.iter().for_each(|channel| write_subframe(&mut writer, channel));
+    writer.align();
+    let frame_crc = crc16(&writer.bytes);
+    writer.write(frame_crc as u64, 16);
+
+    let frame_len = writer.len();
+    out.extend(writer.bytes);
+    frame_len
+}
+
+/// What STREAMINFO records about the encoded audio, known only once it has all been encoded.
+#[derive(Default)]
+struct StreamStats {
+    total_samples: usize,
+    min_frame: u32,
+    max_frame: u32,
+    /// Over the samples as interleaved little-endian bytes
+    md5: Md5,
+}
+
+fn metadata(channels: usize, sample_rate: u32, comments: &[(&str, String)], stats: &StreamStats) -> Vec<u8> {
+    let min_block = if stats.total_samples < BLOCK_SIZE { stats.total_samples } else { BLOCK_SIZE };
+    let mut writer = BitWriter::new();
+    writer.write(0x664C_6143, 32);
+    writer.write(if comments.is_empty() { 1 } else { 0 }, 1);
+    writer.write(0, 7);
+    writer.write(34, 24);
+    writer.write(min_block as u64, 16);
+    writer.write(BLOCK_SIZE as u64, 16);
+    writer.write(if stats.max_frame == 0 { 0 } else { stats.min_frame as u64 }, 24);
+    writer.write(stats.max_frame as u64, 24);
+    writer.write(sample_rate as u64, 20);
+    writer.write(channels as u64 - 1, 3);
+    writer.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    writer.write((stats.total_samples as u64) >> 32, 4);
+    writer.write(stats.total_samples as u64, 32);
+    // MD5 signature of the unencoded audio
+    stats.md5.digest().iter().for_each(|byte| writer.write(*byte as u64, 8));
+
+    if !comments.is_empty() {
+        let vendor = concat!("azure-ost-core ", env!("CARGO_PKG_VERSION")).as_bytes();
+        let block = vorbis_comment_body(vendor, comments);
+        writer.write(1, 1);
+        writer.write(4, 7);
+        writer.write(block.len() as u64, 24);
+        block.iter().for_each(|byte| writer.write(*byte as u64, 8));
+    }
+    writer.bytes
+}
+
+/// Encodes chunks of interleaved 16-bit samples into a FLAC stream written to `out`, a block at a
+/// time. Non-empty `comments` are written to a VORBIS_COMMENT metadata block. STREAMINFO is
+/// filled in once every block has been encoded.
+pub fn encode<W, I>(out: &mut W, samples: I, channels: usize, sample_rate: u32, comments: &[(&str, String)]) -> Result<(), AzureError>
+    where W: Write + Seek, I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+    let write_err = |_| AzureError::ErrorExporting("Writing FLAC");
+    let start = out.seek(SeekFrom::Current(0)).map_err(write_err)?;
+    let mut stats = StreamStats { min_frame: u32::max_value(), ..StreamStats::default() };
+    out.write_all(&metadata(channels, sample_rate, comments, &stats)).map_err(write_err)?;
+
+    let mut frame_number = 0u64;
+    let mut write_block = |block: &[i16], stats: &mut StreamStats| {
+        let block_channels = (0..channels).map(|c| {
+            block.iter().skip(c).step_by(channels).map(|s| *s as i64).collect::<Vec<i64>>()
+        }).collect::<Vec<_>>();
+        let mut frame = Vec::new();
+        let frame_len = write_frame(&mut frame, frame_number, &block_channels) as u32;
+        frame_number += 1;
+        stats.total_samples += block.len() / channels;
+        stats.md5.update(&block.iter().flat_map(|s| vec![*s as u8, (*s >> 8) as u8]).collect::<Vec<u8>>());
+        stats.min_frame = stats.min_frame.min(frame_len);
+        stats.max_frame = stats.max_frame.max(frame_len);
+        out.write_all(&frame).map_err(write_err)
+    };
+
+    let block_len = BLOCK_SIZE * channels;
+    let mut block = Vec::with_capacity(block_len);
+    for chunk in samples {
+        let chunk = chunk?;
+        let mut rest = chunk.as_slice();
+        while !rest.is_empty() {
+            let take = (block_len - block.len()).min(rest.len());
+            block.extend_from_slice(&rest[..take]);
+            rest = &rest[take..];
+            if block.len() == block_len {
+                write_block(&block, &mut stats)?;
+                block.clear();
+            }
+        }
+    }
+    if !block.is_empty() {
+        write_block(&block, &mut stats)?;
+    }
+
+    out.seek(SeekFrom::Start(start))
+        .and_then(|_| out.write_all(&metadata(channels, sample_rate, comments, &stats)))
+        .and_then(|_| out.seek(SeekFrom::End(0)))
+        .map(|_| ())
+        .map_err(write_err)
+}
+
+#[cfg(test)]
+mod flac_tests {
+    extern crate claxon;
+
+    use super::*;
+    use std::io::Cursor;
+
+    fn md5(data: &[u8]) -> [u8; 16] {
+        let mut md5 = Md5::default();
+        data.chunks(7).for_each(|chunk| md5.update(chunk));
+        md5.digest()
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Encodes `data` and decodes it again with claxon, checking the samples and STREAMINFO.
+    fn round_trip(data: &[i16], channels: usize) {
+        let mut encoded = Cursor::new(Vec::new());
+        encode(&mut encoded, data.chunks(3000).map(|chunk| Ok(chunk.to_vec())), channels, 44100, &[]).unwrap();
+        let mut reader = claxon::FlacReader::new(Cursor::new(encoded.into_inner())).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!((info.channels as usize, info.sample_rate), (channels, 44100));
+        assert_eq!(info.samples, Some((data.len() / channels) as u64));
+        let bytes = data.iter().flat_map(|s| vec![*s as u8, (*s >> 8) as u8]).collect::<Vec<u8>>();
+        assert_eq!(info.md5sum, md5(&bytes));
+        let decoded = reader.samples().map(|sample| sample.unwrap() as i16).collect::<Vec<i16>>();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn md5_known_values() {
+        assert_eq!(hex(&md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(&md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(hex(&md5("1234567890".repeat(8).as_bytes())), "57edf4a22be3c955ac49da2e2107b67a");
+    }
+
+    #[test]
+    fn decodes_to_input() {
+        let mut seed = 1u32;
+        let mut noise = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as i16
+        };
+        // a tone, a constant and a noise block, the last one cut short
+        let frames = (0..2 * BLOCK_SIZE + 1000).map(|i| match i / BLOCK_SIZE {
+            0 => (((i as f64) * 0.05).sin() * 12000.0) as i16,
+            1 => -1234,
+            _ => noise(),
+        }).collect::<Vec<i16>>();
+
+        let subframe_type = |samples: &[i16]| {
+            let mut writer = BitWriter::new();
+            write_subframe(&mut writer, &samples.iter().map(|s| *s as i64).collect::<Vec<i64>>());
+            writer.bytes[0] >> 1
+        };
+        assert_eq!(subframe_type(&frames[BLOCK_SIZE..2 * BLOCK_SIZE]), 0);
+        assert_eq!(subframe_type(&frames[2 * BLOCK_SIZE..]), 1);
+
+        round_trip(&frames, 1);
+        let stereo = frames.iter().zip(frames.iter().rev()).flat_map(|(left, right)| vec![*left, *right]).collect::<Vec<i16>>();
+        round_trip(&stereo, 2);
+    }
+
+    #[test]
+    fn crc_known_values() {
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn utf8_frame_numbers() {
+        let mut writer = BitWriter::new();
+        write_utf8_number(&mut writer, 0x7F);
+        write_utf8_number(&mut writer, 0x80);
+        write_utf8_number(&mut writer, 0x800);
+        assert_eq!(writer.bytes, vec![0x7F, 0xC2, 0x80, 0xE0, 0xA0, 0x80]);
+    }
+
+    #[test]
+    fn stream_header() {
+        let data = (0..10000).map(|i| ((i * 37) % 2000) as i16 - 1000).collect::<Vec<i16>>();
+        let mut encoded = ::std::io::Cursor::new(Vec::new());
+        encode(&mut encoded, data.chunks(3000).map(|chunk| Ok(chunk.to_vec())), 2, 44100, &[]).unwrap();
+        let encoded = encoded.into_inner();
+        assert_eq!(&encoded[0..4], b"fLaC");
+        // STREAMINFO header is the last metadata block and 34 bytes long
+        assert_eq!(&encoded[4..8], &[0x80, 0, 0, 34]);
+        // total samples, filled in after encoding
+        assert_eq!(&encoded[22..26], &[0, 0, 0x13, 0x88]);
+        // first frame sync code
+        assert_eq!(&encoded[42..44], &[0xFF, 0xF8]);
+    }
+}
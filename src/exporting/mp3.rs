@@ -0,0 +1,124 @@
+//! MP3 encoding through libmp3lame. Only the handful of LAME functions needed to configure
+//! CBR/VBR/ABR encoding of mono and interleaved stereo PCM are bound here.
+
+use std::io::{Seek, SeekFrom, Write};
+use std::os::raw::{c_int, c_short, c_uchar};
+use ::errors::AzureError;
+use super::{LameSettings, LameBitrateMode};
+
+#[allow(non_camel_case_types)]
+enum lame_global_flags {}
+
+const VBR_OFF: c_int = 0;
+const VBR_ABR: c_int = 3;
+const VBR_MTRH: c_int = 4;
+const LAMETAG_MAX: usize = 2880;
+
+#[link(name = "mp3lame")]
+extern "C" {
+    fn lame_init() -> *mut lame_global_flags;
+    fn lame_close(gfp: *mut lame_global_flags) -> c_int;
+    fn lame_set_in_samplerate(gfp: *mut lame_global_flags, rate: c_int) -> c_int;
+    fn lame_set_num_channels(gfp: *mut lame_global_flags, channels: c_int) -> c_int;
+    fn lame_set_quality(gfp: *mut lame_global_flags, quality: c_int) -> c_int;
+    fn lame_set_brate(gfp: *mut lame_global_flags, kbps: c_int) -> c_int;
+    #[allow(non_snake_case)]
+    fn lame_set_VBR(gfp: *mut lame_global_flags, mode: c_int) -> c_int;
+    #[allow(non_snake_case)]
+    fn lame_set_VBR_q(gfp: *mut lame_global_flags, level: c_int) -> c_int;
+    #[allow(non_snake_case)]
+    fn lame_set_VBR_mean_bitrate_kbps(gfp: *mut lame_global_flags, kbps: c_int) -> c_int;
+    fn lame_init_params(gfp: *mut lame_global_flags) -> c_int;
+    fn lame_encode_buffer(gfp: *mut lame_global_flags, pcm_l: *const c_short, pcm_r: *const c_short, samples_per_channel: c_int,
+                          mp3buf: *mut c_uchar, mp3buf_size: c_int) -> c_int;
+    fn lame_encode_buffer_interleaved(gfp: *mut lame_global_flags, pcm: *mut c_short, samples_per_channel: c_int,
+                                      mp3buf: *mut c_uchar, mp3buf_size: c_int) -> c_int;
+    fn lame_encode_flush(gfp: *mut lame_global_flags, mp3buf: *mut c_uchar, size: c_int) -> c_int;
+    fn lame_get_lametag_frame(gfp: *mut lame_global_flags, buffer: *mut c_uchar, size: usize) -> usize;
+}
+
+/// Owns a LAME encoder context and closes it when dropped.
+struct Lame(*mut lame_global_flags);
+
+impl Drop for Lame {
+    fn drop(&mut self) {
+        unsafe { lame_close(self.0); }
+    }
+}
+
+fn check(code: c_int, reason: &'static str) -> Result<(), AzureError> {
+    if code < 0 { Err(AzureError::ErrorExporting(reason)) } else { Ok(()) }
+}
+
+/// Encodes chunks of mono or interleaved stereo samples into an MP3 stream written to `out`, using
+/// the given settings.
+pub fn encode<W, I>(out: &mut W, samples: I, channels: usize, sample_rate: u64, settings: &LameSettings) -> Result<(), AzureError>
+    where W: Write + Seek, I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+    let lame = unsafe { lame_init() };
+    if lame.is_null() {
+        return Err(AzureError::ErrorExporting("Creating LAME encoder"));
+    }
+    let lame = Lame(lame);
+
+    unsafe {
+        check(lame_set_in_samplerate(lame.0, sample_rate as c_int), "Setting LAME sample rate")?;
+        check(lame_set_num_channels(lame.0, channels as c_int), "Setting LAME channels")?;
+        check(lame_set_quality(lame.0, settings.quality as c_int), "Setting LAME quality")?;
+        match settings.bitrate {
+            LameBitrateMode::CBR(kbps) => {
+                check(lame_set_VBR(lame.0, VBR_OFF), "Setting LAME bitrate mode")?;
+                check(lame_set_brate(lame.0, kbps as c_int), "Setting LAME bitrate")?;
+            },
+            LameBitrateMode::VBR(level) => {
+                check(lame_set_VBR(lame.0, VBR_MTRH), "Setting LAME bitrate mode")?;
+                check(lame_set_VBR_q(lame.0, level as c_int), "Setting LAME VBR level")?;
+            },
+            LameBitrateMode::ABR(kbps) => {
+                check(lame_set_VBR(lame.0, VBR_ABR), "Setting LAME bitrate mode")?;
+                check(lame_set_VBR_mean_bitrate_kbps(lame.0, kbps as c_int), "Setting LAME bitrate")?;
+            },
+        }
+        check(lame_init_params(lame.0), "Initializing LAME parameters")?;
+    }
+
+    let write_err = |_| AzureError::ErrorExporting("Writing MP3");
+    let start = out.seek(SeekFrom::Current(0)).map_err(write_err)?;
+    let mut written = 0usize;
+    for chunk in samples {
+        let mut chunk = chunk?;
+        let samples_per_channel = chunk.len() / channels;
+        // worst case buffer size recommended by LAME
+        let mut buffer = vec![0u8; (5 * samples_per_channel) / 4 + 7200];
+        let encoded = unsafe {
+            if channels == 1 {
+                // the right channel is ignored for mono input
+                lame_encode_buffer(lame.0, chunk.as_ptr(), chunk.as_ptr(), samples_per_channel as c_int,
+                                   buffer.as_mut_ptr(), buffer.len() as c_int)
+            } else {
+                lame_encode_buffer_interleaved(lame.0, chunk.as_mut_ptr(), samples_per_channel as c_int,
+                                               buffer.as_mut_ptr(), buffer.len() as c_int)
+            }
+        };
+        check(encoded, "Encoding MP3")?;
+        out.write_all(&buffer[..encoded as usize]).map_err(write_err)?;
+        written += encoded as usize;
+    }
+    let mut buffer = vec![0u8; 7200];
+    let flushed = unsafe {
+        lame_encode_flush(lame.0, buffer.as_mut_ptr(), buffer.len() as c_int)
+    };
+    check(flushed, "Flushing MP3 encoder")?;
+    out.write_all(&buffer[..flushed as usize]).map_err(write_err)?;
+    written += flushed as usize;
+
+    // replace the placeholder first frame with the Xing/LAME info frame so players can seek VBR
+    let mut tag = vec![0u8; LAMETAG_MAX];
+    let tag_len = unsafe { lame_get_lametag_frame(lame.0, tag.as_mut_ptr(), tag.len()) };
+    if tag_len > 0 && tag_len <= tag.len() && tag_len <= written {
+        out.seek(SeekFrom::Start(start))
+            .and_then(|_| out.write_all(&tag[..tag_len]))
+            .and_then(|_| out.seek(SeekFrom::End(0)))
+            .map_err(write_err)?;
+    }
+    Ok(())
+}
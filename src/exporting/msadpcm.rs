@@ -0,0 +1,165 @@
+//! A decoder for Microsoft ADPCM, read a block at a time so layers can be seeked to directly.
+
+use ::errors::AzureError;
+use ::scd::MsAdpcm;
+use super::stream::LayerSource;
+
+/// Scales the step size by the magnitude of the last nibble, in 1/256ths
+const ADAPTATION: [i32; 16] = [230, 230, 230, 230, 307, 409, 512, 614, 768, 614, 512, 409, 307, 230, 230, 230];
+const MIN_DELTA: i32 = 16;
+
+/// The decoder state of one channel, as loaded from a block header.
+pub struct Channel {
+    pub coefficients: (i32, i32),
+    pub delta: i32,
+    /// The last sample decoded
+    pub sample1: i32,
+    /// The sample before `sample1`
+    pub sample2: i32,
+}
+
+impl Channel {
+    pub fn decode(&mut self, nibble: u8) -> i16 {
+        let signed = if nibble & 0x08 != 0 { nibble as i32 - 0x10 } else { nibble as i32 };
+        let predicted = (self.sample1 * self.coefficients.0 + self.sample2 * self.coefficients.1) >> 8;
+        let sample = (predicted + signed * self.delta).max(i16::min_value() as i32).min(i16::max_value() as i32);
+        self.sample2 = self.sample1;
+        self.sample1 = sample;
+        self.delta = ((ADAPTATION[nibble as usize] * self.delta) >> 8).max(MIN_DELTA);
+        sample as i16
+    }
+
+    /// Picks the nibble that best predicts `sample`, updating the state as decoding it would.
+    #[cfg(any(test, feature="fixtures"))]
+    pub fn encode(&mut self, sample: i16) -> u8 {
+        let predicted = (self.sample1 * self.coefficients.0 + self.sample2 * self.coefficients.1) >> 8;
+        let steps = (sample as i32 - predicted) as f64 / self.delta as f64;
+        let nibble = (steps.round().max(-8.0).min(7.0) as i32 & 0x0F) as u8;
+        self.decode(nibble);
+        nibble
+    }
+}
+
+/// Decodes block `block` into interleaved samples of every channel. The last block may be
+/// shorter than the others.
+pub fn decode_block(adpcm: &MsAdpcm, block: usize) -> Result<Vec<i16>, AzureError> {
+    let channels = adpcm.channels;
+    let start = block * adpcm.block_align;
+    let end = (start + adpcm.block_align).min(adpcm.data.len());
+    let data = adpcm.data.get(start..end).ok_or(AzureError::ErrorDecoding)?;
+    if data.len() < adpcm.block_header_len() {
+        return Err(AzureError::ErrorDecoding);
+    }
+
+    let read_i16 = |offset: usize| (data[offset] as u16 | (data[offset + 1] as u16) << 8) as i16 as i32;
+    let mut states = (0..channels)
+        .map(|channel| {
+            adpcm.coefficients.get(data[channel] as usize)
+                .map(|coefficients| Channel {
+                    coefficients: (coefficients.0 as i32, coefficients.1 as i32),
+                    delta: read_i16(channels + channel * 2),
+                    sample1: read_i16(channels * 3 + channel * 2),
+                    sample2: read_i16(channels * 5 + channel * 2),
+                })
+                .ok_or(AzureError::ErrorDecoding)
+        })
+        .collect::<Result<Vec<_>, AzureError>>()?;
+
+    let nibbles = &data[adpcm.block_header_len()..];
+    let mut samples = Vec::with_capacity((nibbles.len() * 2 / channels + 2) * channels);
+    // the header holds the first two samples, oldest last
+    samples.extend(states.iter().map(|state| state.sample2 as i16));
+    samples.extend(states.iter().map(|state| state.sample1 as i16));
+    // nibbles are interleaved across channels, high nibble first
+    let nibbles = nibbles.iter().flat_map(|byte| vec![byte >> 4, byte & 0x0F]);
+    let whole_frames = (data.len() - adpcm.block_header_len()) * 2 / channels * channels;
+    for (i, nibble) in nibbles.take(whole_frames).enumerate() {
+        samples.push(states[i % channels].decode(nibble));
+    }
+    Ok(samples)
+}
+
+/// One layer of MSADPCM audio, decoded a block at a time.
+pub struct AdpcmLayerSource<'a> {
+    adpcm: &'a MsAdpcm,
+    /// The entry channels making up each channel of the layer
+    channels: Vec<usize>,
+    block: usize,
+    /// The frames to drop from the start of the next block, after a seek into its middle
+    skip: usize,
+}
+
+impl<'a> AdpcmLayerSource<'a> {
+    pub fn new(adpcm: &'a MsAdpcm, channels: Vec<usize>) -> Result<AdpcmLayerSource<'a>, AzureError> {
+        if channels.iter().any(|channel| *channel >= adpcm.channels) {
+            return Err(AzureError::ErrorDecoding);
+        }
+        Ok(AdpcmLayerSource { adpcm, channels, block: 0, skip: 0 })
+    }
+}
+
+impl<'a> LayerSource for AdpcmLayerSource<'a> {
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), AzureError> {
+        let frames_per_block = self.adpcm.frames_per_block();
+        self.block = frame / frames_per_block;
+        self.skip = frame % frames_per_block;
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        if self.block * self.adpcm.block_align >= self.adpcm.data.len() {
+            return Ok(None);
+        }
+        let samples = decode_block(self.adpcm, self.block)?;
+        self.block += 1;
+        let layer = samples.chunks(self.adpcm.channels)
+            .skip(self.skip)
+            .flat_map(|frame| self.channels.iter().map(move |channel| frame[*channel]))
+            .collect::<Vec<i16>>();
+        self.skip = 0;
+        Ok(Some(layer))
+    }
+}
+
+#[cfg(test)]
+mod msadpcm_tests {
+    use super::*;
+
+    fn adpcm(data: Vec<u8>) -> MsAdpcm {
+        MsAdpcm {
+            channels: 2,
+            rate: 44100,
+            block_align: 18,
+            coefficients: vec![(256, 0), (512, -256)],
+            loop_points: None,
+            data,
+        }
+    }
+
+    #[test]
+    fn decodes_block() {
+        // predictors 0 and 1, deltas 16 and 32, sample1 100 and -100, sample2 50 and -50
+        let mut block = vec![0, 1, 16, 0, 32, 0, 100, 0, 0x9C, 0xFF, 50, 0, 0xCE, 0xFF];
+        block.extend_from_slice(&[0x1F, 0x00, 0x70, 0x80]);
+        let adpcm = adpcm(block);
+        assert_eq!(adpcm.frames(), 6);
+        // the header samples, then the nibbles 1, 0, 7, -8 and -1, 0, 0, 0
+        assert_eq!(decode_block(&adpcm, 0).unwrap(), vec![
+            50, -50, 100, -100, 116, -182, 116, -264, 228, -346, -76, -428,
+        ]);
+    }
+
+    #[test]
+    fn seeks_within_blocks() {
+        let block = vec![0, 0, 16, 0, 16, 0, 2, 0, 4, 0, 1, 0, 3, 0, 0, 0, 0, 0];
+        let adpcm = adpcm(block.iter().chain(block.iter()).cloned().collect());
+        let mut layer = AdpcmLayerSource::new(&adpcm, vec![0, 1]).unwrap();
+        layer.seek(9).unwrap();
+        assert_eq!(layer.read().unwrap(), Some(vec![2, 4, 2, 4, 2, 4]));
+        assert_eq!(layer.read().unwrap(), None);
+    }
+}
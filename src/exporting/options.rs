@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use ::errors::AzureError;
+use ::sqpack_blue::sheet::ex::SheetLanguage;
+use super::template::PathTemplate;
+
+/// Controls how many times the loop region of a track is played in rendered exports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopPolicy {
+    /// Play the loop region this many times. `Count(2)` plays it through twice. Exports fail if
+    /// the repeats go on past `MAX_LOOP_DURATION` seconds.
+    Count(usize),
+    /// Repeat the loop region until the track, before the fade, is at least this many seconds long.
+    /// The loop region is always played at least once. At most `MAX_LOOP_DURATION` seconds.
+    MinimumDuration(f32),
+}
+
+/// The longest `LoopPolicy::MinimumDuration` accepted, an hour, which keeps every WAV export well
+/// under its 4 GiB limit.
+pub const MAX_LOOP_DURATION: f32 = 3600.0;
+
+/// The shape of the fade-out, as a gain curve going from 1 to 0.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FadeCurve {
+    /// Gain falls at a constant rate.
+    Linear,
+    /// Gain falls at a constant rate in decibels (60 dB over the fade), dropping quickly at first.
+    Exponential,
+    /// Gain follows a quarter cosine, keeping the perceived loudness steadier than linear.
+    EqualPower,
+    /// Gain stays high for most of the fade and drops off at the end.
+    Logarithmic,
+}
+
+/// How long the fade-out lasts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FadeLength {
+    /// A fixed number of seconds. `Seconds(0.0)` disables the fade.
+    Seconds(f32),
+    /// A percentage of the rendered track length.
+    Percent(f32),
+    /// Whichever of a number of seconds or a percentage of the track is shorter.
+    LesserOf(f32, f32),
+}
+
+/// Controls the fade-out applied to the end of rendered exports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FadeSettings {
+    pub curve: FadeCurve,
+    pub length: FadeLength,
+    /// When set, the fade starts exactly at the end of the last loop iteration and fades over
+    /// the continuing loop instead of the track's outro. Has no effect on tracks without loop
+    /// points.
+    pub from_loop_end: bool,
+}
+
+/// How the files of an export are named.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputNaming {
+    /// The SCD path without its leading `music` folder, e.g. `ffxiv/BGM_Field_Gri_01`.
+    ScdPath,
+    /// The track's in-game title from the Orchestrion sheet in the given language, e.g.
+    /// `ffxiv/Serenity`. Tracks without an Orchestrion entry fall back to `ScdPath`.
+    Orchestrion(SheetLanguage),
+}
+
+/// What to do when an exported file would be written to a path already used earlier in the run.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CollisionPolicy {
+    /// Write to a free path with a numbered suffix, e.g. `Serenity_2.ogg`.
+    Suffix,
+    /// Leave the file out of the export.
+    Skip,
+    /// Write to the same path, replacing the earlier file.
+    Overwrite,
+    /// Fail the track with `AzureError::OutputCollision`.
+    Error,
+}
+
+/// How the channels of an SCD entry are grouped into layers, each exported as its own file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChannelLayout {
+    /// Channels are paired into stereo layers. A channel left without a pair, as in mono tracks or
+    /// the last channel of an odd count, becomes a layer of its own, copied to both sides.
+    Upmix,
+    /// As `Upmix`, but a channel left without a pair is exported as a mono layer.
+    KeepMono,
+    /// Lists the layers of entries with a given channel count. Each layer names the entry channels
+    /// making up its output channels, one for mono or two for stereo, e.g. `vec![vec![0, 1],
+    /// vec![2, 2]]` for a stereo layer and an upmixed one. Entries with other channel counts fall
+    /// back to `Upmix`.
+    Map(BTreeMap<usize, Vec<Vec<usize>>>),
+}
+
+/// The order in which tracks are handed to the export threads.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum WorkOrder {
+    /// The order of the BGM sheet.
+    Listed,
+    /// The largest SCDs first, so the longest tracks do not start last and leave the other threads
+    /// idle at the end of the run. The sizes are measured while hashing, so the hashing phase runs
+    /// even without a manifest, journal or `OverwritePolicy::IfSourceChanged`.
+    LargestFirst,
+}
+
+/// Options that apply to every export, regardless of the `ExportMode` used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportOptions {
+    /// How the loop region is repeated. Defaults to playing it twice.
+    pub loop_policy: LoopPolicy,
+    /// The fade-out applied to the end of the track. Defaults to a linear fade over the lesser of
+    /// 30 seconds or 5% of the track.
+    pub fade: FadeSettings,
+    /// When set, tracks with loop points are written as two files instead of one render:
+    /// `<name>_intro` holding the audio before the loop and `<name>_loop` holding the loop body,
+    /// neither looped nor faded, so a game engine can play the intro once and then repeat the
+    /// loop. Tracks without loop points are rendered as usual, and `ExportMode::Passthrough`
+    /// ignores this option. WAV and FLAC files join back sample-exactly. MP3 files carry LAME's
+    /// encoder delay and padding, which only players reading the LAME tag trim. Opus files keep
+    /// their exact length through their pre-skip and final granule position, but are resampled to
+    /// 48 kHz one file at a time, so the seam is not sample-exact.
+    pub split_intro: bool,
+    /// When set, an `ExportSidecar` JSON file is written next to every exported file, sharing its
+    /// name with a `.json` extension.
+    pub sidecar: bool,
+    /// When set, exported files are tagged with the track title, album, track number (the BGM
+    /// sheet row), entry and layer, and `LOOPSTART`/`LOOPLENGTH` when the audio has loop points.
+    /// OGG, Opus and FLAC get Vorbis comments and MP3 an ID3v2 tag. WAV and `Passthrough` files
+    /// are left untagged, the latter to stay byte-identical to the game's stream. Defaults to off,
+    /// so existing callers keep getting untagged files.
+    pub tags: bool,
+    /// The name given by the `{name}` field of `path_template`. Defaults to
+    /// `OutputNaming::ScdPath`. Orchestrion titles are also used as the title tag.
+    pub naming: OutputNaming,
+    /// The path of each exported file relative to the export directory. Defaults to
+    /// `{name}{_entry}{_layer}{_part}{ext}`, mirroring the sqpack folders.
+    pub path_template: PathTemplate,
+    /// What to do when two tracks map to the same path. Paths are sanitized for every platform
+    /// first and compared case-insensitively. Since tracks are exported in parallel, which of the
+    /// colliding tracks gets the plain path is not fixed. Defaults to `CollisionPolicy::Suffix`.
+    pub on_collision: CollisionPolicy,
+    /// A journal file, for example next to the export directory, recording each finished layer
+    /// and track with its SCD hash. A run given the journal of an interrupted run skips the work
+    /// it finished. See `Journal`. Defaults to none.
+    pub journal: Option<PathBuf>,
+    /// The order in which tracks are exported. Defaults to `WorkOrder::Listed`.
+    pub work_order: WorkOrder,
+    /// How entry channels are grouped into layers. Defaults to `ChannelLayout::Upmix`, which
+    /// writes only stereo files.
+    pub channel_layout: ChannelLayout,
+}
+
+impl Default for ExportOptions {
+    fn default() -> ExportOptions {
+        ExportOptions {
+            loop_policy: LoopPolicy::Count(2),
+            fade: FadeSettings::default(),
+            split_intro: false,
+            sidecar: false,
+            tags: false,
+            naming: OutputNaming::ScdPath,
+            path_template: PathTemplate::default(),
+            on_collision: CollisionPolicy::Suffix,
+            journal: None,
+            work_order: WorkOrder::Listed,
+            channel_layout: ChannelLayout::Upmix,
+        }
+    }
+}
+
+impl ExportOptions {
+    pub fn validate(&self) -> Result<(), AzureError> {
+        match self.loop_policy {
+            LoopPolicy::Count(0) =>
+                Err(AzureError::InvalidExportSettings("Loop count must be at least 1")),
+            LoopPolicy::MinimumDuration(seconds) if !(seconds > 0.0 && seconds <= MAX_LOOP_DURATION) =>
+                Err(AzureError::InvalidExportSettings("Loop duration must be a positive number of seconds, up to an hour")),
+            _ => Ok(()),
+        }.and_then(|_| self.fade.length.validate())
+            .and_then(|_| self.channel_layout.validate())
+    }
+}
+
+impl Default for FadeSettings {
+    fn default() -> FadeSettings {
+        FadeSettings {
+            curve: FadeCurve::Linear,
+            length: FadeLength::LesserOf(30.0, 5.0),
+            from_loop_end: false,
+        }
+    }
+}
+
+impl FadeCurve {
+    /// The gain at `progress`, which runs from 0 at the start of the fade to 1 at its end.
+    pub fn gain(&self, progress: f32) -> f32 {
+        use std::f32::consts::FRAC_PI_2;
+        let progress = progress.max(0.0).min(1.0);
+        match self {
+            FadeCurve::Linear => 1.0 - progress,
+            FadeCurve::Exponential => (10f32.powf(-3.0 * progress) - 0.001) / 0.999,
+            FadeCurve::EqualPower => (progress * FRAC_PI_2).cos(),
+            FadeCurve::Logarithmic => (1.0 + 9.0 * (1.0 - progress)).log10(),
+        }
+    }
+}
+
+impl FadeLength {
+    fn validate(&self) -> Result<(), AzureError> {
+        let valid_seconds = |seconds: f32| seconds >= 0.0 && seconds.is_finite();
+        let valid_percent = |percent: f32| percent >= 0.0 && percent <= 100.0;
+        let valid = match *self {
+            FadeLength::Seconds(seconds) => valid_seconds(seconds),
+            FadeLength::Percent(percent) => valid_percent(percent),
+            FadeLength::LesserOf(seconds, percent) => valid_seconds(seconds) && valid_percent(percent),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(AzureError::InvalidExportSettings("Fade length must be a non-negative number of seconds or a percentage up to 100"))
+        }
+    }
+
+    /// The fade length in frames for a track of `total_frames` frames at `rate` Hz.
+    pub fn frames(&self, total_frames: usize, rate: u64) -> usize {
+        let seconds = |seconds: f32| (seconds as f64 * rate as f64) as usize;
+        let percent = |percent: f32| (total_frames as f64 * percent as f64 / 100.0) as usize;
+        match *self {
+            FadeLength::Seconds(s) => seconds(s),
+            FadeLength::Percent(p) => percent(p),
+            FadeLength::LesserOf(s, p) => seconds(s).min(percent(p)),
+        }.min(total_frames)
+    }
+}
+
+impl ChannelLayout {
+    fn validate(&self) -> Result<(), AzureError> {
+        let layers = match self {
+            ChannelLayout::Map(layers) => layers,
+            _ => return Ok(()),
+        };
+        let valid = layers.iter().all(|(channels, layers)| {
+            !layers.is_empty() && layers.iter().all(|layer| {
+                (layer.len() == 1 || layer.len() == 2) && layer.iter().all(|channel| channel < channels)
+            })
+        });
+        if valid {
+            Ok(())
+        } else {
+            Err(AzureError::InvalidExportSettings("Channel map layers must take one or two of the entry's channels"))
+        }
+    }
+
+    /// The layers of an entry with `channels` channels, as the entry channels making up each
+    /// layer's output channels.
+    pub fn layers(&self, channels: usize) -> Vec<Vec<usize>> {
+        let lone_channel = |channel: usize| match self {
+            ChannelLayout::KeepMono => vec![channel],
+            _ => vec![channel, channel],
+        };
+        match self {
+            ChannelLayout::Map(layers) if layers.contains_key(&channels) => layers[&channels].clone(),
+            _ => (0..channels).step_by(2)
+                .map(|left| if left + 1 < channels { vec![left, left + 1] } else { lone_channel(left) })
+                .collect(),
+        }
+    }
+}
+
+impl LoopPolicy {
+    /// The number of times to play a loop region of `loop_frames` frames in a track of
+    /// `total_frames` frames at `rate` Hz.
+    pub fn iterations(&self, total_frames: usize, loop_frames: usize, rate: u64) -> usize {
+        match *self {
+            LoopPolicy::Count(count) => count,
+            LoopPolicy::MinimumDuration(seconds) => {
+                let target = (seconds as f64 * rate as f64).ceil() as usize;
+                if total_frames >= target || loop_frames == 0 {
+                    1
+                } else {
+                    1 + (target - total_frames + loop_frames - 1) / loop_frames
+                }
+            },
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Ogg Opus encoding of mono or interleaved stereo 16-bit PCM using libopus.
+
+extern crate audiopus;
+
+use std::io::Write;
+use ::errors::AzureError;
+use ::ogg::{PacketWriter, PacketWriteEndInfo};
+use self::audiopus::{Application, Bitrate, Channels, SampleRate};
+use self::audiopus::coder::Encoder;
+use super::resample::Resampler;
+use super::tags::vorbis_comment_body;
+
+/// Opus always operates at 48 kHz internally.
+pub const OPUS_RATE: u64 = 48000;
+/// 20 ms frames.
+const FRAME_SIZE: usize = 960;
+const MAX_PACKET: usize = 4000;
+const STREAM_SERIAL: u32 = 0x4F70_7573;
+
+fn opus_head(channels: u8, pre_skip: u16, input_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend_from_slice(b"OpusHead");
+    head.push(1);
+    head.push(channels);
+    head.extend_from_slice(&[pre_skip as u8, (pre_skip >> 8) as u8]);
+    head.extend_from_slice(&[input_rate as u8, (input_rate >> 8) as u8, (input_rate >> 16) as u8, (input_rate >> 24) as u8]);
+    // output gain, channel mapping family 0
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+fn opus_tags(comments: &[(&str, String)]) -> Vec<u8> {
+    let vendor = concat!("azure-ost-core ", env!("CARGO_PKG_VERSION")).as_bytes();
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend(vorbis_comment_body(vendor, comments));
+    tags
+}
+
+/// Resamples chunks of mono or interleaved stereo samples from `sample_rate` to 48 kHz and encodes them
+/// into an Ogg Opus stream written to `out`, at the given bitrate in kilobits per second and with
+/// `comments` in its OpusTags header.
+pub fn encode<W, I>(out: &mut W, samples: I, channels: usize, sample_rate: u64, kilobitrate: u32, comments: &[(&str, String)]) -> Result<(), AzureError>
+    where W: Write, I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+    let layout = if channels == 1 { Channels::Mono } else { Channels::Stereo };
+    let mut encoder = Encoder::new(SampleRate::Hz48000, layout, Application::Audio)
+        .map_err(|_| AzureError::ErrorExporting("Creating Opus encoder"))?;
+    encoder.set_bitrate(Bitrate::BitsPerSecond(kilobitrate as i32 * 1000))
+        .map_err(|_| AzureError::ErrorExporting("Setting Opus bitrate"))?;
+    let pre_skip = encoder.lookahead()
+        .map_err(|_| AzureError::ErrorExporting("Reading Opus lookahead"))? as usize;
+
+    let mut writer = PacketWriter::new(out);
+    let write_err = |_| AzureError::ErrorExporting("Writing Ogg Opus stream");
+    writer.write_packet(opus_head(channels as u8, pre_skip as u16, sample_rate as u32).into_boxed_slice(),
+                        STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0).map_err(write_err)?;
+    writer.write_packet(opus_tags(comments).into_boxed_slice(),
+                        STREAM_SERIAL, PacketWriteEndInfo::EndPage, 0).map_err(write_err)?;
+
+    let mut resampler = Resampler::new(channels, sample_rate, OPUS_RATE);
+    let mut pcm = Vec::with_capacity(FRAME_SIZE * channels);
+    let mut total_frames = 0usize;
+    let mut packet = vec![0u8; MAX_PACKET];
+    // each packet is held back until the next one, as only the last packet ends the stream
+    let mut held: Option<Vec<u8>> = None;
+    let mut packet_count = 0usize;
+    let mut encode_frames = |pcm: &mut Vec<i16>, held: &mut Option<Vec<u8>>, packet_count: &mut usize| -> Result<(), AzureError> {
+        let frames_end = pcm.len() - pcm.len() % (FRAME_SIZE * channels);
+        for frame in pcm[..frames_end].chunks(FRAME_SIZE * channels) {
+            let len = encoder.encode(frame, &mut packet)
+                .map_err(|_| AzureError::ErrorExporting("Encoding Opus"))?;
+            if let Some(previous) = held.replace(packet[..len].to_vec()) {
+                writer.write_packet(previous.into_boxed_slice(), STREAM_SERIAL, PacketWriteEndInfo::NormalPacket,
+                                    (*packet_count * FRAME_SIZE) as u64).map_err(write_err)?;
+            }
+            *packet_count += 1;
+        }
+        pcm.drain(..frames_end);
+        Ok(())
+    };
+
+    for chunk in samples {
+        let resampled = resampler.push(&chunk?);
+        total_frames += resampled.len() / channels;
+        pcm.extend(resampled);
+        encode_frames(&mut pcm, &mut held, &mut packet_count)?;
+    }
+    let resampled = resampler.finish();
+    total_frames += resampled.len() / channels;
+    pcm.extend(resampled);
+    // pad so the encoder delay is flushed and the last packet is complete
+    let final_count = (total_frames + pre_skip + FRAME_SIZE - 1) / FRAME_SIZE;
+    let padded_len = (final_count - packet_count) * FRAME_SIZE * channels;
+    pcm.resize(padded_len.max(pcm.len()), 0);
+    encode_frames(&mut pcm, &mut held, &mut packet_count)?;
+
+    match held {
+        Some(last) => writer.write_packet(last.into_boxed_slice(), STREAM_SERIAL, PacketWriteEndInfo::EndStream,
+                                          (pre_skip + total_frames) as u64).map_err(write_err),
+        None => Ok(()),
+    }
+}
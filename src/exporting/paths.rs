@@ -0,0 +1,180 @@
+//! Keeps output paths valid on every platform and unique within a run.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use ::errors::AzureError;
+use super::options::CollisionPolicy;
+
+/// Leaves room below the usual 255 byte limit for collision suffixes and temporary file names.
+const MAX_COMPONENT_LEN: usize = 200;
+
+fn is_reserved(component: &str) -> bool {
+    let stem = component.split('.').next().unwrap_or("").trim_end().to_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" => true,
+        _ => {
+            (stem.starts_with("COM") || stem.starts_with("LPT"))
+                && stem.len() == 4
+                && stem[3..].chars().all(|c| c >= '1' && c <= '9')
+        },
+    }
+}
+
+/// Truncates `text` to at most `max_len` bytes without splitting a character.
+fn truncate(text: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Makes a single path component safe to use on Windows, macOS and Linux: characters Windows
+/// forbids become `_`, trailing dots and spaces are dropped, reserved device names such as `CON`
+/// get a trailing `_`, and overlong names are shortened while keeping their extension.
+pub fn sanitize_component(component: &str) -> String {
+    let replaced = component.chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+    let mut sanitized = replaced.trim_end_matches(|c| c == '.' || c == ' ').to_string();
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    if is_reserved(&sanitized) {
+        let insert_at = sanitized.find('.').unwrap_or(sanitized.len());
+        sanitized.insert(insert_at, '_');
+    }
+    if sanitized.len() > MAX_COMPONENT_LEN {
+        sanitized = match sanitized.rfind('.').filter(|dot| sanitized.len() - dot < 16) {
+            Some(dot) => {
+                let extension = sanitized[dot..].to_string();
+                format!("{}{}", truncate(&sanitized[..dot], MAX_COMPONENT_LEN - extension.len()), extension)
+            },
+            None => truncate(&sanitized, MAX_COMPONENT_LEN).to_string(),
+        };
+    }
+    sanitized
+}
+
+/// Sanitizes every component of a `/` separated relative path. Empty, `.` and `..` components
+/// are replaced so the path cannot leave the export directory.
+pub fn sanitize_path(path: &str) -> String {
+    path.split(|c| c == '/' || c == '\\')
+        .map(|component| match component {
+            "" | "." | ".." => "_".to_string(),
+            component => sanitize_component(component),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The path of an SCD without its leading `music` folder and its `.scd` extension, both matched
+/// case-insensitively, e.g. `ffxiv/BGM_Field_Gri_01`. Loose files outside a `music` folder keep
+/// their folders.
+pub fn scd_base_path(scd_path: &str) -> &str {
+    let path = match scd_path.find('/') {
+        Some(slash) if scd_path[..slash].eq_ignore_ascii_case("music") => &scd_path[slash + 1..],
+        _ => scd_path,
+    };
+    let stem_len = path.len().saturating_sub(4);
+    if path.is_char_boundary(stem_len) && path[stem_len..].eq_ignore_ascii_case(".scd") {
+        &path[..stem_len]
+    } else {
+        path
+    }
+}
+
+/// Inserts `_<n>` before the extension of the last component of `path`.
+fn with_suffix(path: &str, n: usize) -> String {
+    let name_start = path.rfind('/').map_or(0, |slash| slash + 1);
+    match path[name_start..].rfind('.') {
+        Some(dot) => format!("{}_{}{}", &path[..name_start + dot], n, &path[name_start + dot..]),
+        None => format!("{}_{}", path, n),
+    }
+}
+
+/// The output paths written during one run, used to detect two tracks mapping to the same file.
+/// Paths are compared case-insensitively, as they would be on Windows and macOS.
+#[derive(Debug, Default)]
+pub struct OutputPaths {
+    claimed: Mutex<HashSet<String>>,
+}
+
+impl OutputPaths {
+    pub fn new() -> OutputPaths {
+        OutputPaths::default()
+    }
+
+    /// Claims a path written by an earlier run, as returned by `claim`, so that no file of this run
+    /// takes it.
+    pub fn reserve(&self, path: &str) {
+        self.claimed.lock().unwrap().insert(path.to_lowercase());
+    }
+
+    /// Sanitizes `path` and claims it, resolving a collision with an earlier claim according to
+    /// `policy`. Returns the path to write to, or `None` if the file should be skipped.
+    pub fn claim(&self, path: &str, policy: CollisionPolicy) -> Result<Option<String>, AzureError> {
+        let path = sanitize_path(path);
+        let mut claimed = self.claimed.lock().unwrap();
+        if claimed.insert(path.to_lowercase()) {
+            return Ok(Some(path));
+        }
+        match policy {
+            CollisionPolicy::Suffix => {
+                let suffixed = (2..)
+                    .map(|n| with_suffix(&path, n))
+                    .find(|suffixed| !claimed.contains(&suffixed.to_lowercase()))
+                    .unwrap();
+                claimed.insert(suffixed.to_lowercase());
+                Ok(Some(suffixed))
+            },
+            CollisionPolicy::Skip => Ok(None),
+            CollisionPolicy::Overwrite => Ok(Some(path)),
+            CollisionPolicy::Error => Err(AzureError::OutputCollision(path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod paths_tests {
+    use super::*;
+
+    #[test]
+    fn sanitizes_components() {
+        assert_eq!(sanitize_path("ex1/What: \"Is\" Love?.ogg"), "ex1/What_ _Is_ Love_.ogg");
+        assert_eq!(sanitize_path("../con.ogg"), "_/con_.ogg");
+        assert_eq!(sanitize_path("ffxiv/LPT1"), "ffxiv/LPT1_");
+        assert_eq!(sanitize_path("Answers.../COM10.flac"), "Answers/COM10.flac");
+        let long = sanitize_component(&format!("{}.flac", "é".repeat(150)));
+        assert!(long.len() <= MAX_COMPONENT_LEN);
+        assert!(long.ends_with("é.flac"));
+    }
+
+    #[test]
+    fn scd_base_paths() {
+        assert_eq!(scd_base_path("music/ffxiv/BGM_Field_01.scd"), "ffxiv/BGM_Field_01");
+        assert_eq!(scd_base_path("Music/ex1/Track.SCD"), "ex1/Track");
+        assert_eq!(scd_base_path("Loose.SCD"), "Loose");
+        assert_eq!(scd_base_path("a/Track.scd"), "a/Track");
+    }
+
+    #[test]
+    fn collision_policies() {
+        let paths = OutputPaths::new();
+        assert_eq!(paths.claim("a/Song.ogg", CollisionPolicy::Suffix).unwrap(), Some("a/Song.ogg".to_string()));
+        assert_eq!(paths.claim("a/song.ogg", CollisionPolicy::Suffix).unwrap(), Some("a/song_2.ogg".to_string()));
+        assert_eq!(paths.claim("a/Song.ogg", CollisionPolicy::Suffix).unwrap(), Some("a/Song_3.ogg".to_string()));
+        assert_eq!(paths.claim("a/Song.ogg", CollisionPolicy::Skip).unwrap(), None);
+        assert_eq!(paths.claim("a/Song.ogg", CollisionPolicy::Overwrite).unwrap(), Some("a/Song.ogg".to_string()));
+        assert!(paths.claim("a/Song.ogg", CollisionPolicy::Error).is_err());
+
+        let paths = OutputPaths::new();
+        paths.reserve("a/Song_2.ogg");
+        assert_eq!(paths.claim("a/Song.ogg", CollisionPolicy::Suffix).unwrap(), Some("a/Song.ogg".to_string()));
+        assert_eq!(paths.claim("a/song.ogg", CollisionPolicy::Suffix).unwrap(), Some("a/song_3.ogg".to_string()));
+    }
+}
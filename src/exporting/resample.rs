@@ -0,0 +1,159 @@
+//! A polyphase windowed-sinc resampler for interleaved 16-bit PCM.
+
+use std::f64::consts::PI;
+
+/// Filter taps on each side of the interpolation point.
+const HALF_TAPS: usize = 16;
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+fn blackman(x: f64, width: f64) -> f64 {
+    if x.abs() >= width {
+        return 0.0;
+    }
+    let n = (x / width + 1.0) / 2.0;
+    0.42 - 0.5 * (2.0 * PI * n).cos() + 0.08 * (4.0 * PI * n).cos()
+}
+
+/// Builds one filter per output phase. Phase `p` interpolates at `p / phases` of the way between
+/// two input samples.
+fn build_filters(phases: usize, cutoff: f64) -> Vec<Vec<f64>> {
+    (0..phases).map(|phase| {
+        let offset = phase as f64 / phases as f64;
+        let taps = (0..HALF_TAPS * 2).map(|tap| {
+            let x = tap as f64 - (HALF_TAPS as f64 - 1.0) - offset;
+            cutoff * sinc(cutoff * x) * blackman(x, HALF_TAPS as f64)
+        }).collect::<Vec<f64>>();
+        let sum: f64 = taps.iter().sum();
+        taps.into_iter().map(|t| t / sum).collect()
+    }).collect()
+}
+
+/// Resamples interleaved samples from one rate to another a chunk at a time, keeping only the input
+/// the filter can still reach.
+pub struct Resampler {
+    channels: usize,
+    from_rate: u64,
+    to_rate: u64,
+    step: u64,
+    phases: u64,
+    filters: Vec<Vec<f64>>,
+    /// The buffered input, starting at frame `input_start`
+    input: Vec<i16>,
+    input_start: usize,
+    /// The number of frames pushed so far
+    input_frames: usize,
+    next_output: u64,
+}
+
+impl Resampler {
+    pub fn new(channels: usize, from_rate: u64, to_rate: u64) -> Resampler {
+        let divisor = gcd(from_rate, to_rate);
+        let phases = to_rate / divisor;
+        let filters = if from_rate == to_rate {
+            Vec::new()
+        } else {
+            let cutoff = (to_rate as f64 / from_rate as f64).min(1.0) * 0.97;
+            build_filters(phases as usize, cutoff)
+        };
+        Resampler {
+            channels, from_rate, to_rate,
+            step: from_rate / divisor,
+            phases,
+            filters,
+            input: Vec::new(),
+            input_start: 0,
+            input_frames: 0,
+            next_output: 0,
+        }
+    }
+
+    /// The input frame the filter of output frame `n` is centred on.
+    fn base(&self, n: u64) -> isize {
+        (n * self.step / self.phases) as isize
+    }
+
+    fn sample_at(&self, frame: isize, channel: usize) -> f64 {
+        if frame < 0 || frame as usize >= self.input_frames {
+            0.0
+        } else {
+            self.input[(frame as usize - self.input_start) * self.channels + channel] as f64
+        }
+    }
+
+    fn push_output(&mut self, out: &mut Vec<i16>) {
+        let n = self.next_output;
+        let base = self.base(n);
+        let filter = &self.filters[(n * self.step % self.phases) as usize];
+        for channel in 0..self.channels {
+            let value = filter.iter().enumerate().fold(0f64, |acc, (tap, coefficient)| {
+                acc + coefficient * self.sample_at(base + tap as isize - (HALF_TAPS as isize - 1), channel)
+            });
+            out.push(value.round().max(i16::min_value() as f64).min(i16::max_value() as f64) as i16);
+        }
+        self.next_output += 1;
+    }
+
+    /// Resamples the next chunk of input, returning the output frames it completes.
+    pub fn push(&mut self, data: &[i16]) -> Vec<i16> {
+        if self.from_rate == self.to_rate {
+            return data.to_vec();
+        }
+        self.input.extend_from_slice(data);
+        self.input_frames += data.len() / self.channels;
+        let mut out = Vec::new();
+        // an output frame is complete once the last input frame its filter reaches has arrived
+        while self.base(self.next_output) + (HALF_TAPS as isize) < self.input_frames as isize {
+            self.push_output(&mut out);
+        }
+        let keep_from = (self.base(self.next_output) - (HALF_TAPS as isize - 1)).max(0) as usize;
+        if keep_from > self.input_start {
+            self.input.drain(..(keep_from - self.input_start) * self.channels);
+            self.input_start = keep_from;
+        }
+        out
+    }
+
+    /// Returns the remaining output, treating the input as silent past its end.
+    pub fn finish(&mut self) -> Vec<i16> {
+        if self.from_rate == self.to_rate {
+            return Vec::new();
+        }
+        let out_frames = (self.input_frames as u64 * self.to_rate + self.from_rate - 1) / self.from_rate;
+        let mut out = Vec::new();
+        while self.next_output < out_frames {
+            self.push_output(&mut out);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod resample_tests {
+    use super::*;
+
+    #[test]
+    fn identity_rate() {
+        let data = vec![1i16, 2, 3, 4];
+        let mut resampler = Resampler::new(2, 48000, 48000);
+        assert_eq!(resampler.push(&data), data);
+        assert!(resampler.finish().is_empty());
+    }
+
+    #[test]
+    fn output_length_and_dc() {
+        let data = vec![1000i16; 44100 * 2];
+        let mut resampler = Resampler::new(2, 44100, 48000);
+        let mut out = data.chunks(1000).flat_map(|chunk| resampler.push(chunk)).collect::<Vec<i16>>();
+        out.extend(resampler.finish());
+        assert_eq!(out.len(), 48000 * 2);
+        // away from the edges a constant signal stays constant
+        assert!(out[2000..90000].iter().all(|s| (*s - 1000).abs() <= 1));
+    }
+}
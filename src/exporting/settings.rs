@@ -0,0 +1,117 @@
+use ::errors::AzureError;
+
+/// The quality presets offered by the Vorbis encoder, from largest output to smallest.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum VorbisPreset {
+    VeryHighQuality,
+    HighQuality,
+    Quality,
+    Medium,
+    Performance,
+    HighPerformance,
+    VeryHighPerformance,
+}
+
+/// Settings for the Vorbis encoder used by `ExportMode::OGG`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VorbisSettings {
+    /// Use one of the encoder's presets directly.
+    Preset(VorbisPreset),
+    /// A VBR quality level in the usual Vorbis range of -0.1 to 1.0. The encoder only offers
+    /// presets, so the level is mapped onto the closest one (-0.1 being `VeryHighPerformance` and
+    /// 1.0 being `VeryHighQuality`).
+    Level(f32),
+}
+
+/// The bitrate control used by LAME.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LameBitrateMode {
+    /// Constant bitrate in kilobits per second.
+    CBR(u32),
+    /// Variable bitrate at the given level, 0 being the highest quality and 9 the lowest.
+    VBR(u8),
+    /// Average bitrate in kilobits per second.
+    ABR(u32),
+}
+
+/// Settings for the LAME encoder used by `ExportMode::MP3`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LameSettings {
+    pub bitrate: LameBitrateMode,
+    /// The algorithm quality, 0 being the best (and slowest) and 9 the worst.
+    pub quality: u8,
+}
+
+const CBR_BITRATES: [u32; 18] = [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 192, 224, 256, 320];
+
+impl Default for VorbisSettings {
+    fn default() -> VorbisSettings {
+        VorbisSettings::Preset(VorbisPreset::Medium)
+    }
+}
+
+impl VorbisSettings {
+    pub fn validate(&self) -> Result<(), AzureError> {
+        match self {
+            VorbisSettings::Level(level) if !(*level >= -0.1 && *level <= 1.0) =>
+                Err(AzureError::InvalidExportSettings("Vorbis quality level must be between -0.1 and 1.0")),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn preset(&self) -> VorbisPreset {
+        use self::VorbisPreset::*;
+        match self {
+            VorbisSettings::Preset(preset) => *preset,
+            VorbisSettings::Level(level) => {
+                let presets = [VeryHighPerformance, HighPerformance, Performance, Medium, Quality, HighQuality, VeryHighQuality];
+                let step = ((level + 0.1) / 1.1 * 6.0).round().max(0.0).min(6.0) as usize;
+                presets[step]
+            },
+        }
+    }
+}
+
+impl Default for LameSettings {
+    fn default() -> LameSettings {
+        LameSettings { bitrate: LameBitrateMode::CBR(128), quality: 3 }
+    }
+}
+
+impl LameSettings {
+    pub fn validate(&self) -> Result<(), AzureError> {
+        if self.quality > 9 {
+            return Err(AzureError::InvalidExportSettings("LAME quality must be between 0 and 9"));
+        }
+        match self.bitrate {
+            LameBitrateMode::CBR(kbps) if !CBR_BITRATES.contains(&kbps) =>
+                Err(AzureError::InvalidExportSettings("LAME CBR bitrate must be a standard MP3 bitrate")),
+            LameBitrateMode::VBR(level) if level > 9 =>
+                Err(AzureError::InvalidExportSettings("LAME VBR level must be between 0 and 9")),
+            LameBitrateMode::ABR(kbps) if kbps < 8 || kbps > 310 =>
+                Err(AzureError::InvalidExportSettings("LAME ABR bitrate must be between 8 and 310 kbps")),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod settings_tests {
+    use super::*;
+
+    #[test]
+    fn vorbis_levels_map_to_presets() {
+        assert_eq!(VorbisSettings::Level(-0.1).preset(), VorbisPreset::VeryHighPerformance);
+        assert_eq!(VorbisSettings::Level(0.4).preset(), VorbisPreset::Medium);
+        assert_eq!(VorbisSettings::Level(1.0).preset(), VorbisPreset::VeryHighQuality);
+        assert!(VorbisSettings::Level(1.5).validate().is_err());
+    }
+
+    #[test]
+    fn lame_validation() {
+        assert!(LameSettings::default().validate().is_ok());
+        assert!(LameSettings { bitrate: LameBitrateMode::CBR(130), quality: 3 }.validate().is_err());
+        assert!(LameSettings { bitrate: LameBitrateMode::VBR(2), quality: 10 }.validate().is_err());
+        assert!(LameSettings { bitrate: LameBitrateMode::ABR(192), quality: 2 }.validate().is_ok());
+    }
+}
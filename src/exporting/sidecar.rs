@@ -0,0 +1,36 @@
+use serde::Serialize;
+use serde::Deserialize;
+
+/// Metadata written next to an exported file when `ExportOptions::sidecar` is set, relating the
+/// exported audio back to its source. All positions are in sample frames at `sample_rate`, before
+/// any resampling done by the encoder (as with Opus).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportSidecar {
+    /// The SCD file inside the sqpack, e.g. `music/ffxiv/BGM_Field_Gri_01.scd`
+    pub scd_path: String,
+    /// The row of the BGM sheet the SCD was referenced by
+    pub bgm_index: usize,
+    /// The index of the entry inside the SCD
+    pub entry_index: usize,
+    /// The 1-based layer of the entry, matching the `_layer` suffix of split files
+    pub layer: usize,
+    /// The number of layers in the entry
+    pub layer_count: usize,
+    pub sample_rate: u64,
+    /// The number of channels in the source entry
+    pub channels: usize,
+    /// The source entry channels making up each channel of the exported audio, e.g. `[2, 2]` for
+    /// the third channel upmixed to stereo
+    #[serde(default)]
+    pub layer_channels: Vec<usize>,
+    /// The original `LoopStart` of the source entry, if it has loop points
+    pub loop_start: Option<usize>,
+    /// The original `LoopEnd` of the source entry, if it has loop points
+    pub loop_end: Option<usize>,
+    /// Positions in the exported audio where playback jumps back to the start of the loop
+    pub loop_seams: Vec<usize>,
+    /// Where the fade-out begins in the exported audio, if it was faded
+    pub fade_start: Option<usize>,
+    /// The length of the exported audio
+    pub frames: usize,
+}
@@ -0,0 +1,464 @@
+//! Streams one layer from the decoder to the encoder in chunks, applying the loop and fade
+//! on the way, so the memory an export needs does not grow with the length of the track.
+
+use std::io::Cursor;
+use std::ops::Range;
+use ::errors::AzureError;
+use ::scd::ScdEntry;
+use super::lewton::inside_ogg::OggStreamReader;
+use super::msadpcm::AdpcmLayerSource;
+use super::{ExportOptions, FadeCurve, LoopPolicy, MAX_LOOP_DURATION, LoopInfo, RenderInfo, extract_loop_info, interleave, last_granule_position};
+
+/// Interleaved mono or stereo audio that can be read in chunks from any frame.
+pub trait LayerSource {
+    /// The number of interleaved channels in each frame.
+    fn channels(&self) -> usize;
+
+    /// Positions the source so the next chunk starts at `frame`.
+    fn seek(&mut self, frame: usize) -> Result<(), AzureError>;
+
+    /// Reads the next chunk of interleaved samples, or `None` at the end of the audio.
+    fn read(&mut self) -> Result<Option<Vec<i16>>, AzureError>;
+}
+
+impl<'a> LayerSource for Box<LayerSource + 'a> {
+    fn channels(&self) -> usize {
+        (**self).channels()
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), AzureError> {
+        (**self).seek(frame)
+    }
+
+    fn read(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        (**self).read()
+    }
+}
+
+/// The channel count, sample rate, length and loop points of an SCD entry.
+pub struct StreamInfo {
+    pub channels: usize,
+    pub rate: u64,
+    pub frames: usize,
+    pub loop_info: Option<LoopInfo>,
+}
+
+fn open_ogg(data: &[u8]) -> Result<OggStreamReader<Cursor<&[u8]>>, AzureError> {
+    OggStreamReader::new(Cursor::new(data)).map_err(|_| AzureError::ErrorDecoding)
+}
+
+/// Reads the headers of an Ogg Vorbis stream, taking its length from the granule position of its
+/// last page rather than decoding it.
+fn probe_ogg(data: &[u8]) -> Result<StreamInfo, AzureError> {
+    let osr = open_ogg(data)?;
+    let frames = last_granule_position(data).ok_or(AzureError::ErrorDecoding)?;
+    Ok(StreamInfo {
+        channels: osr.ident_hdr.audio_channels as usize,
+        rate: osr.ident_hdr.audio_sample_rate as u64,
+        frames: frames as usize,
+        loop_info: extract_loop_info(osr.comment_hdr.comment_list),
+    })
+}
+
+/// Reads the layout of an SCD entry. MSADPCM entries are measured from their block layout and
+/// take their loop points from the entry header.
+pub fn probe(entry: &ScdEntry) -> Result<StreamInfo, AzureError> {
+    match entry {
+        ScdEntry::Ogg(data) => probe_ogg(data),
+        ScdEntry::MsAdpcm(adpcm) => Ok(StreamInfo {
+            channels: adpcm.channels,
+            rate: adpcm.rate,
+            frames: adpcm.frames(),
+            loop_info: adpcm.loop_points.map(|(start, end)| LoopInfo { start, end }),
+        }),
+    }
+}
+
+/// A layer of an SCD entry, in whichever codec it is stored. `channels` lists the entry channels
+/// making up each channel of the layer.
+pub fn layer_source<'a>(entry: &'a ScdEntry, channels: Vec<usize>) -> Result<Box<LayerSource + 'a>, AzureError> {
+    if channels.is_empty() || channels.len() > 2 {
+        return Err(AzureError::ErrorExporting("Layers must be mono or stereo"));
+    }
+    Ok(match entry {
+        ScdEntry::Ogg(data) => Box::new(OggLayerSource::new(data, channels)?),
+        ScdEntry::MsAdpcm(adpcm) => Box::new(AdpcmLayerSource::new(adpcm, channels)?),
+    })
+}
+
+/// How far before the target frame a backward seek first lands, doubled until it lands early
+/// enough.
+const SEEK_PREROLL: usize = 4096;
+
+/// One layer of an Ogg Vorbis stream, decoded a packet at a time. Seeking backwards bisects the
+/// stream for a page shortly before the target, rather than decoding it again from its start.
+pub struct OggLayerSource<'a> {
+    data: &'a [u8],
+    /// The stream channels making up each channel of the layer
+    channels: Vec<usize>,
+    reader: OggStreamReader<Cursor<&'a [u8]>>,
+    /// The frame just past the last one decoded by `reader`
+    decoded: usize,
+    /// The decoded samples past the frame last seeked to, returned by the next read
+    pending: Option<Vec<i16>>,
+    /// The granule position of the last page
+    last_granule: Option<u64>,
+}
+
+impl<'a> OggLayerSource<'a> {
+    pub fn new(data: &'a [u8], channels: Vec<usize>) -> Result<OggLayerSource<'a>, AzureError> {
+        let last_granule = last_granule_position(data);
+        Ok(OggLayerSource { data, channels, reader: open_ogg(data)?, decoded: 0, pending: None, last_granule })
+    }
+
+    /// Decodes the layer's channels of the next packet, which are empty for a packet that only
+    /// primes the decoder.
+    fn decode_packet(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        let packet = match self.reader.read_dec_packet().map_err(|_| AzureError::ErrorDecoding)? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        self.channels.iter()
+            .map(|channel| packet.get(*channel).cloned())
+            .collect::<Option<Vec<_>>>()
+            .ok_or(AzureError::ErrorDecoding)
+            .map(|channels| Some(interleave(channels)))
+    }
+
+    fn decode(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        while let Some(samples) = self.decode_packet()? {
+            if !samples.is_empty() {
+                self.decoded += samples.len() / self.channels.len();
+                return Ok(Some(samples));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The frame the next read starts at.
+    fn position(&self) -> usize {
+        self.decoded - self.pending.as_ref().map_or(0, |pending| pending.len() / self.channels.len())
+    }
+
+    /// Moves the reader back to `frame` or shortly before it. After a page seek the position of
+    /// the decoded audio is unknown until a page ends, so what is decoded until then is kept as
+    /// pending. The last page is not trusted, as the decoder only trims the end of the stream
+    /// when it knew its position beforehand.
+    fn rewind(&mut self, frame: usize) -> Result<(), AzureError> {
+        let channels = self.channels.len();
+        let mut preroll = SEEK_PREROLL;
+        while frame > preroll {
+            self.reader.seek_absgp_pg((frame - preroll) as u64).map_err(|_| AzureError::ErrorDecoding)?;
+            let mut decoded = Vec::new();
+            let granule = loop {
+                match self.decode_packet()? {
+                    Some(samples) => decoded.extend(samples),
+                    None => break None,
+                }
+                if let Some(granule) = self.reader.get_last_absgp() {
+                    break Some(granule);
+                }
+            };
+            let landed = granule
+                .filter(|granule| Some(*granule) != self.last_granule)
+                .and_then(|granule| (granule as usize).checked_sub(decoded.len() / channels).map(|start| (granule, start)));
+            match landed {
+                Some((granule, start)) if start <= frame => {
+                    self.decoded = granule as usize;
+                    self.pending = if decoded.is_empty() { None } else { Some(decoded) };
+                    return Ok(());
+                },
+                _ => preroll *= 2,
+            }
+        }
+        self.reader = open_ogg(self.data)?;
+        self.decoded = 0;
+        self.pending = None;
+        Ok(())
+    }
+}
+
+impl<'a> LayerSource for OggLayerSource<'a> {
+    fn channels(&self) -> usize {
+        self.channels.len()
+    }
+
+    fn seek(&mut self, frame: usize) -> Result<(), AzureError> {
+        if frame < self.position() {
+            self.rewind(frame)?;
+        }
+        let channels = self.channels.len();
+        let current = self.position();
+        if frame < self.decoded {
+            self.pending = self.pending.take().map(|pending| pending[(frame - current) * channels..].to_vec());
+            return Ok(());
+        }
+        self.pending = None;
+        while self.decoded < frame {
+            let start = self.decoded;
+            match self.decode()? {
+                Some(chunk) => if self.decoded > frame {
+                    self.pending = Some(chunk[(frame - start) * channels..].to_vec());
+                },
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        match self.pending.take() {
+            Some(pending) => Ok(Some(pending)),
+            None => self.decode(),
+        }
+    }
+}
+
+/// How one output is rendered from its layer: the ranges of source frames played one after the
+/// other, and the fade-out over the end of the result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderPlan {
+    segments: Vec<Range<usize>>,
+    fade_length: usize,
+    curve: FadeCurve,
+}
+
+impl RenderPlan {
+    /// Plays the source frames in `range` once, without a fade.
+    pub fn once(range: Range<usize>) -> RenderPlan {
+        RenderPlan { segments: vec![range], fade_length: 0, curve: FadeCurve::Linear }
+    }
+
+    /// Applies the loop policy and fade-out from the export options to a layer of `frames` frames.
+    /// Fails if a `LoopPolicy::Count` repeats the loop past `MAX_LOOP_DURATION` seconds.
+    pub fn render(frames: usize, loop_info: Option<LoopInfo>, rate: u64, options: &ExportOptions) -> Result<(RenderPlan, RenderInfo), AzureError> {
+        let fade_settings = &options.fade;
+        let (segments, loop_seams, fade_length) = match loop_info.filter(|info| info.fits(frames)) {
+            Some(info) => {
+                let loop_frames = info.end - info.start;
+                let iterations = options.loop_policy.iterations(frames, loop_frames, rate).max(1);
+                // checked before the segments are listed, as a huge count would exhaust memory
+                let loop_end = loop_frames.checked_mul(iterations - 1)
+                    .and_then(|repeats| repeats.checked_add(info.end))
+                    .filter(|loop_end| match options.loop_policy {
+                        LoopPolicy::Count(_) => iterations == 1 || *loop_end as f64 <= MAX_LOOP_DURATION as f64 * rate as f64,
+                        LoopPolicy::MinimumDuration(_) => true,
+                    })
+                    .ok_or(AzureError::InvalidExportSettings("Loop count repeats the track past an hour"))?;
+                let mut loop_seams = (1..iterations).map(|i| info.end + loop_frames * (i - 1)).collect::<Vec<usize>>();
+                let mut segments = vec![0..info.end];
+                segments.extend((1..iterations).map(|_| info.start..info.end));
+                if fade_settings.from_loop_end {
+                    // drop the outro, then keep playing the loop while fading out
+                    let fade_length = fade_settings.length.frames(loop_end, rate);
+                    if fade_length > 0 {
+                        loop_seams.push(loop_end);
+                    }
+                    let mut remaining = fade_length;
+                    while remaining > 0 {
+                        let length = remaining.min(loop_frames);
+                        segments.push(info.start..info.start + length);
+                        remaining -= length;
+                    }
+                    (segments, loop_seams, fade_length)
+                } else {
+                    segments.push(info.end..frames);
+                    let fade_length = fade_settings.length.frames(loop_end + frames - info.end, rate);
+                    (segments, loop_seams, fade_length)
+                }
+            },
+            None => (vec![0..frames], Vec::new(), fade_settings.length.frames(frames, rate)),
+        };
+        let plan = RenderPlan { segments, fade_length, curve: fade_settings.curve };
+        let fade_start = if fade_length > 0 { Some(plan.frames() - fade_length) } else { None };
+        Ok((plan, RenderInfo { loop_seams, fade_start }))
+    }
+
+    /// The length of the rendered output in frames.
+    pub fn frames(&self) -> usize {
+        self.segments.iter().map(|segment| segment.end.saturating_sub(segment.start)).sum()
+    }
+}
+
+/// The rendered output of a plan as chunks of interleaved samples, read from the source only as
+/// they are needed.
+pub struct Rendered<'p, S> {
+    source: S,
+    plan: &'p RenderPlan,
+    fade_start: usize,
+    segment: usize,
+    /// The frames of the current segment already output
+    offset: usize,
+    /// The frame the source will read from next, if known
+    source_at: Option<usize>,
+    /// The frames output so far
+    output: usize,
+    failed: bool,
+}
+
+impl<'p, S: LayerSource> Rendered<'p, S> {
+    pub fn new(source: S, plan: &'p RenderPlan) -> Rendered<'p, S> {
+        let fade_start = plan.frames() - plan.fade_length;
+        Rendered { source, plan, fade_start, segment: 0, offset: 0, source_at: Some(0), output: 0, failed: false }
+    }
+
+    fn next_segment(&mut self) {
+        self.segment += 1;
+        self.offset = 0;
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+        while let Some(segment) = self.plan.segments.get(self.segment).cloned() {
+            let start = segment.start + self.offset;
+            if start >= segment.end {
+                self.next_segment();
+                continue;
+            }
+            if self.source_at != Some(start) {
+                self.source.seek(start)?;
+            }
+            let mut chunk = match self.source.read()? {
+                Some(chunk) => chunk,
+                None => Vec::new(),
+            };
+            if chunk.is_empty() {
+                // the audio ended before the segment did
+                self.source_at = None;
+                self.next_segment();
+                continue;
+            }
+            let channels = self.source.channels();
+            self.source_at = Some(start + chunk.len() / channels);
+            chunk.truncate((segment.end - start) * channels);
+            let frames = chunk.len() / channels;
+            self.offset += frames;
+            self.fade(&mut chunk);
+            self.output += frames;
+            return Ok(Some(chunk));
+        }
+        Ok(None)
+    }
+
+    /// Fades out the samples of `chunk` that fall within the last `fade_length` frames.
+    fn fade(&self, chunk: &mut [i16]) {
+        let (output, fade_start, fade_length) = (self.output, self.fade_start, self.plan.fade_length);
+        let curve = self.plan.curve;
+        chunk.chunks_mut(self.source.channels()).enumerate()
+            .filter(|(frame, _)| output + frame >= fade_start)
+            .for_each(|(frame, samples)| {
+                let gain = curve.gain((output + frame - fade_start) as f32 / fade_length as f32);
+                samples.iter_mut().for_each(|sample| {
+                    (*sample) = (*sample as f32 * gain) as i16;
+                });
+            });
+    }
+}
+
+impl<'p, S: LayerSource> Iterator for Rendered<'p, S> {
+    type Item = Result<Vec<i16>, AzureError>;
+
+    fn next(&mut self) -> Option<Result<Vec<i16>, AzureError>> {
+        if self.failed {
+            return None;
+        }
+        match self.next_chunk() {
+            Ok(chunk) => chunk.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod stream_tests {
+    use super::*;
+    use super::super::{FadeLength, FadeSettings, LoopPolicy};
+
+    /// Serves interleaved samples from memory a few frames at a time.
+    struct MemorySource {
+        samples: Vec<i16>,
+        at: usize,
+    }
+
+    impl LayerSource for MemorySource {
+        fn channels(&self) -> usize {
+            2
+        }
+
+        fn seek(&mut self, frame: usize) -> Result<(), AzureError> {
+            self.at = frame * 2;
+            Ok(())
+        }
+
+        fn read(&mut self) -> Result<Option<Vec<i16>>, AzureError> {
+            let end = (self.at + 6).min(self.samples.len());
+            let chunk = self.samples[self.at..end].to_vec();
+            self.at = end;
+            Ok(if chunk.is_empty() { None } else { Some(chunk) })
+        }
+    }
+
+    fn render(samples: Vec<i16>, loop_info: LoopInfo, options: &ExportOptions) -> (Vec<i16>, RenderInfo) {
+        let (plan, render) = RenderPlan::render(samples.len() / 2, Some(loop_info), 1, options).unwrap();
+        let rendered = Rendered::new(MemorySource { samples, at: 0 }, &plan)
+            .collect::<Result<Vec<Vec<i16>>, AzureError>>().unwrap().concat();
+        assert_eq!(rendered.len() / 2, plan.frames());
+        (rendered, render)
+    }
+
+    #[test]
+    fn loop_count() {
+        let unfaded = |loop_policy: LoopPolicy| ExportOptions {
+            loop_policy,
+            fade: FadeSettings { length: FadeLength::Seconds(0.0), ..FadeSettings::default() },
+            ..ExportOptions::default()
+        };
+        let info = LoopInfo { start: 1, end: 3 };
+        let samples = vec![0i16, 0, 1, 1, 2, 2, 3, 3];
+        assert_eq!(render(samples.clone(), info, &unfaded(LoopPolicy::Count(1))).0, samples);
+        assert_eq!(render(samples.clone(), info, &unfaded(LoopPolicy::Count(2))).0,
+                   vec![0, 0, 1, 1, 2, 2, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(render(samples.clone(), info, &unfaded(LoopPolicy::Count(3))).0.len(), 16);
+        // loop end past the audio is ignored
+        assert_eq!(render(samples.clone(), LoopInfo { start: 1, end: 5 }, &unfaded(LoopPolicy::Count(2))).0, samples);
+
+        // repeats are capped at an hour, here of 1 Hz audio
+        let max_count = MAX_LOOP_DURATION as usize / 2 - 1;
+        assert!(RenderPlan::render(4, Some(info), 1, &unfaded(LoopPolicy::Count(max_count))).is_ok());
+        assert!(RenderPlan::render(4, Some(info), 1, &unfaded(LoopPolicy::Count(max_count + 1))).is_err());
+        assert!(RenderPlan::render(4, Some(info), 1, &unfaded(LoopPolicy::Count(usize::max_value()))).is_err());
+        assert!(RenderPlan::render(4, Some(info), 1, &unfaded(LoopPolicy::MinimumDuration(MAX_LOOP_DURATION))).is_ok());
+    }
+
+    #[test]
+    fn fade_from_loop_end() {
+        let options = ExportOptions {
+            fade: FadeSettings { curve: FadeCurve::Linear, length: FadeLength::Seconds(2.0), from_loop_end: true },
+            ..ExportOptions::default()
+        };
+        let samples = vec![100i16, 100, 200, 200, 300, 300, 900, 900];
+        let (rendered, render) = render(samples, LoopInfo { start: 1, end: 3 }, &options);
+        // intro, two loop iterations, then two faded frames of the loop instead of the outro
+        assert_eq!(rendered, vec![100, 100, 200, 200, 300, 300, 200, 200, 300, 300, 200, 200, 150, 150]);
+        assert_eq!(render.loop_seams, vec![3, 5]);
+        assert_eq!(render.fade_start, Some(5));
+    }
+
+    #[test]
+    fn ogg_seeks_backwards() {
+        let ogg = ::fixtures::FixtureEntry::new(2, 44100, 60000).ogg_vorbis().unwrap();
+        let mut source = OggLayerSource::new(&ogg, vec![1, 0]).unwrap();
+        let mut decoded = Vec::new();
+        while let Some(chunk) = source.read().unwrap() {
+            decoded.extend(chunk);
+        }
+        assert_eq!(decoded.len(), 60000 * 2);
+        for frame in &[50000, 30001, 12345, 100, 0] {
+            source.seek(*frame).unwrap();
+            let chunk = source.read().unwrap().unwrap();
+            assert_eq!(chunk[..], decoded[frame * 2..frame * 2 + chunk.len()], "seeking to {}", frame);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+//! Metadata tags for exported files: Vorbis comments (Ogg Vorbis, Opus and FLAC) and ID3v2 (MP3).
+
+use std::io::Write;
+use ::errors::AzureError;
+use ::ogg::{OggReadError, Packet, PacketWriter, PacketWriteEndInfo};
+use ::ogg::reading::{BasePacketReader, OggPage, PageParser};
+
+/// The tags describing one exported file.
+#[derive(Debug, Clone)]
+pub struct TrackTags {
+    pub title: String,
+    pub album: String,
+    pub track_number: usize,
+    /// The SCD entry, only set for SCDs holding more than one entry
+    pub entry: Option<usize>,
+    /// The 1-based layer, only set for entries holding more than one layer
+    pub layer: Option<usize>,
+    /// The loop start and end in frames, when the exported audio has loop points
+    pub loop_points: Option<(usize, usize)>,
+}
+
+impl TrackTags {
+    /// The tags as Vorbis comment fields.
+    pub fn comments(&self) -> Vec<(&'static str, String)> {
+        let mut comments = vec![
+            ("TITLE", self.title.clone()),
+            ("ALBUM", self.album.clone()),
+            ("TRACKNUMBER", self.track_number.to_string()),
+        ];
+        if let Some(entry) = self.entry {
+            comments.push(("ENTRY", entry.to_string()));
+        }
+        if let Some(layer) = self.layer {
+            comments.push(("LAYER", layer.to_string()));
+        }
+        if let Some((start, end)) = self.loop_points {
+            comments.push(("LOOPSTART", start.to_string()));
+            comments.push(("LOOPLENGTH", (end - start).to_string()));
+        }
+        comments
+    }
+
+    /// Rescales the loop points for audio resampled from `from_rate` to `to_rate`.
+    #[cfg(feature="opus")]
+    pub fn resampled(&self, from_rate: u64, to_rate: u64) -> TrackTags {
+        let scale = |frame: usize| (frame as u64 * to_rate / from_rate) as usize;
+        TrackTags {
+            loop_points: self.loop_points.map(|(start, end)| (scale(start), scale(end))),
+            ..self.clone()
+        }
+    }
+}
+
+/// The expansion a music folder (the second component of `music/ex1/...`) belongs to.
+pub fn expansion_name(folder: &str) -> Option<&'static str> {
+    match folder {
+        "ffxiv" => Some("A Realm Reborn"),
+        "ex1" => Some("Heavensward"),
+        "ex2" => Some("Stormblood"),
+        "ex3" => Some("Shadowbringers"),
+        "ex4" => Some("Endwalker"),
+        "ex5" => Some("Dawntrail"),
+        _ => None,
+    }
+}
+
+/// The album an SCD belongs to, named after the expansion its folder belongs to when known.
+pub fn album_name(scd_path: &str) -> String {
+    match scd_path.split('/').nth(1).and_then(expansion_name) {
+        Some(expansion) => format!("FINAL FANTASY XIV: {}", expansion),
+        None => "FINAL FANTASY XIV".to_string(),
+    }
+}
+
+fn push_u32_le(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+/// The body shared by Vorbis comment headers, OpusTags packets and FLAC VORBIS_COMMENT blocks.
+pub fn vorbis_comment_body(vendor: &[u8], comments: &[(&str, String)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_u32_le(&mut body, vendor.len() as u32);
+    body.extend_from_slice(vendor);
+    push_u32_le(&mut body, comments.len() as u32);
+    comments.iter().for_each(|(key, value)| {
+        let field = format!("{}={}", key, value);
+        push_u32_le(&mut body, field.len() as u32);
+        body.extend_from_slice(field.as_bytes());
+    });
+    body
+}
+
+/// Reads the vendor string out of a Vorbis comment header packet.
+fn vorbis_vendor(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 11 || &packet[0..7] != b"\x03vorbis" {
+        return None;
+    }
+    let len = packet[7..11].iter().rev().fold(0usize, |acc, byte| (acc << 8) | *byte as usize);
+    packet.get(11..11 + len)
+}
+
+/// Replaces the comment header of an Ogg Vorbis stream as it is written out, keeping its vendor
+/// string and page layout. Only the pages not yet complete are buffered.
+pub struct VorbisRetagger<W: Write> {
+    comments: Vec<(&'static str, String)>,
+    /// The bytes of the next page, once it has started arriving
+    buffer: Vec<u8>,
+    reader: BasePacketReader,
+    writer: PacketWriter<W>,
+    packet_index: usize,
+}
+
+impl<W: Write> VorbisRetagger<W> {
+    pub fn new(out: W, comments: Vec<(&'static str, String)>) -> VorbisRetagger<W> {
+        VorbisRetagger {
+            comments,
+            buffer: Vec::new(),
+            reader: BasePacketReader::new(),
+            writer: PacketWriter::new(out),
+            packet_index: 0,
+        }
+    }
+
+    /// Takes the next part of the encoded stream, writing out every packet it completes.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), AzureError> {
+        let read_err = |_| AzureError::ErrorExporting("Reading encoded Vorbis stream");
+        self.buffer.extend_from_slice(data);
+        while let Some(page) = self.next_page().map_err(read_err)? {
+            self.reader.push_page(page).map_err(read_err)?;
+            while let Some(packet) = self.reader.read_packet() {
+                self.write_packet(packet)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the page at the start of the buffer, if it has fully arrived.
+    fn next_page(&mut self) -> Result<Option<OggPage>, OggReadError> {
+        if self.buffer.len() < 27 {
+            return Ok(None);
+        }
+        let mut header = [0u8; 27];
+        header.copy_from_slice(&self.buffer[..27]);
+        let (mut parser, segments_len) = PageParser::new(header)?;
+        let segments_end = 27 + segments_len;
+        if self.buffer.len() < segments_end {
+            return Ok(None);
+        }
+        let page_end = segments_end + parser.parse_segments(self.buffer[27..segments_end].to_vec());
+        if self.buffer.len() < page_end {
+            return Ok(None);
+        }
+        let page = parser.parse_packet_data(self.buffer[segments_end..page_end].to_vec())?;
+        self.buffer.drain(..page_end);
+        Ok(Some(page))
+    }
+
+    fn write_packet(&mut self, packet: Packet) -> Result<(), AzureError> {
+        let end_info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+        let data = if self.packet_index == 1 {
+            let mut header = b"\x03vorbis".to_vec();
+            header.extend(vorbis_comment_body(vorbis_vendor(&packet.data).unwrap_or(b""), &self.comments));
+            header.push(1);
+            header
+        } else {
+            packet.data
+        };
+        self.packet_index += 1;
+        self.writer.write_packet(data.into_boxed_slice(), serial, end_info, absgp)
+            .map_err(|_| AzureError::ErrorExporting("Writing tagged Vorbis stream"))
+    }
+}
+
+#[cfg(feature="lamemp3")]
+fn id3_text(text: &str) -> Vec<u8> {
+    // UTF-16 with a byte order mark, as ID3v2.3 has no UTF-8 encoding
+    let mut out = vec![0xFF, 0xFE];
+    text.encode_utf16().for_each(|unit| out.extend_from_slice(&[unit as u8, (unit >> 8) as u8]));
+    out
+}
+
+#[cfg(feature="lamemp3")]
+fn id3_frame(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    let len = body.len() as u32;
+    out.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    out.extend_from_slice(&[0, 0]);
+    out.extend_from_slice(body);
+}
+
+/// Builds an ID3v2.3 tag. Fields without a standard frame are written as `TXXX` frames.
+#[cfg(feature="lamemp3")]
+pub fn id3v2(comments: &[(&str, String)]) -> Vec<u8> {
+    let mut frames = Vec::new();
+    comments.iter().for_each(|(key, value)| {
+        let standard = match *key {
+            "TITLE" => Some(b"TIT2"),
+            "ALBUM" => Some(b"TALB"),
+            "TRACKNUMBER" => Some(b"TRCK"),
+            _ => None,
+        };
+        let mut body = vec![1u8];
+        match standard {
+            Some(id) => {
+                body.extend(id3_text(value));
+                id3_frame(&mut frames, id, &body);
+            },
+            None => {
+                body.extend(id3_text(key));
+                body.extend_from_slice(&[0, 0]);
+                body.extend(id3_text(value));
+                id3_frame(&mut frames, b"TXXX", &body);
+            },
+        }
+    });
+    let len = frames.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    // the tag size is a syncsafe integer
+    tag.extend_from_slice(&[(len >> 21) as u8 & 0x7F, (len >> 14) as u8 & 0x7F, (len >> 7) as u8 & 0x7F, len as u8 & 0x7F]);
+    tag.extend(frames);
+    tag
+}
+
+#[cfg(test)]
+mod tags_tests {
+    use super::*;
+
+    fn tags() -> TrackTags {
+        TrackTags {
+            title: "BGM_Field_Gri_01".into(),
+            album: "FINAL FANTASY XIV".into(),
+            track_number: 12,
+            entry: None,
+            layer: Some(2),
+            loop_points: Some((441, 882)),
+        }
+    }
+
+    #[test]
+    fn comment_fields() {
+        let comments = tags().comments();
+        assert_eq!(comments[2], ("TRACKNUMBER", "12".to_string()));
+        assert_eq!(comments[3], ("LAYER", "2".to_string()));
+        assert_eq!(comments[5], ("LOOPLENGTH", "441".to_string()));
+    }
+
+    #[cfg(feature="opus")]
+    #[test]
+    fn resampled_loop_points() {
+        assert_eq!(tags().resampled(44100, 48000).loop_points, Some((480, 960)));
+    }
+
+    #[test]
+    fn album_from_path() {
+        assert_eq!(album_name("music/ex2/BGM_EX2_Town_01.scd"), "FINAL FANTASY XIV: Stormblood");
+        assert_eq!(album_name("sound/BGM_Test.scd"), "FINAL FANTASY XIV");
+    }
+
+    #[test]
+    fn comment_body() {
+        let body = vorbis_comment_body(b"v", &[("A", "b".to_string())]);
+        assert_eq!(body, vec![1, 0, 0, 0, b'v', 1, 0, 0, 0, 3, 0, 0, 0, b'A', b'=', b'b']);
+    }
+
+    #[test]
+    fn retag_in_pieces() {
+        use std::io::Cursor;
+        use ::ogg::PacketReader;
+        let mut stream = PacketWriter::new(Vec::new());
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend(vorbis_comment_body(b"encoder", &[("TITLE", "old".to_string())]));
+        comment.push(1);
+        let packets = vec![(b"\x01vorbis".to_vec(), PacketWriteEndInfo::EndPage), (comment, PacketWriteEndInfo::NormalPacket),
+                           (b"\x05vorbis".to_vec(), PacketWriteEndInfo::EndPage), (vec![7u8; 600], PacketWriteEndInfo::EndStream)];
+        for (data, end_info) in packets {
+            stream.write_packet(data.into_boxed_slice(), 1, end_info, 0).unwrap();
+        }
+
+        let mut retagger = VorbisRetagger::new(Vec::new(), vec![("TITLE", "new".to_string())]);
+        stream.into_inner().chunks(7).for_each(|piece| retagger.write(piece).unwrap());
+        let mut reader = PacketReader::new(Cursor::new(retagger.writer.into_inner()));
+        reader.read_packet().unwrap();
+        let mut expected = b"\x03vorbis".to_vec();
+        expected.extend(vorbis_comment_body(b"encoder", &[("TITLE", "new".to_string())]));
+        expected.push(1);
+        assert_eq!(reader.read_packet().unwrap().unwrap().data, expected);
+        reader.read_packet().unwrap();
+        assert!(reader.read_packet().unwrap().unwrap().last_in_stream());
+    }
+
+    #[cfg(feature="lamemp3")]
+    #[test]
+    fn id3_header() {
+        let tag = id3v2(&[("TITLE", "a".to_string())]);
+        // frame header, encoding, BOM and one UTF-16 code unit
+        assert_eq!(tag.len(), 10 + 10 + 1 + 2 + 2);
+        assert_eq!(&tag[0..10], &[b'I', b'D', b'3', 3, 0, 0, 0, 0, 0, 15]);
+        assert_eq!(&tag[10..14], b"TIT2");
+    }
+}
@@ -0,0 +1,248 @@
+//! Output path templates such as `{expansion}/{index:04}_{title}{_layer}{ext}`.
+
+use ::errors::AzureError;
+use super::ExportSource;
+use super::tags::expansion_name;
+use super::paths::{sanitize_component, scd_base_path};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Index,
+    Entry,
+    EntryCount,
+    Layer,
+    LayerCount,
+    ScdPath,
+    ScdFolder,
+    ScdName,
+    Expansion,
+    Title,
+    Name,
+    EntrySuffix,
+    LayerSuffix,
+    PartSuffix,
+    Ext,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        match name {
+            "index" => Some(Field::Index),
+            "entry" => Some(Field::Entry),
+            "entry_count" => Some(Field::EntryCount),
+            "layer" => Some(Field::Layer),
+            "layer_count" => Some(Field::LayerCount),
+            "scd_path" => Some(Field::ScdPath),
+            "scd_folder" => Some(Field::ScdFolder),
+            "scd_name" => Some(Field::ScdName),
+            "expansion" => Some(Field::Expansion),
+            "title" => Some(Field::Title),
+            "name" => Some(Field::Name),
+            "_entry" => Some(Field::EntrySuffix),
+            "_layer" => Some(Field::LayerSuffix),
+            "_part" => Some(Field::PartSuffix),
+            "ext" => Some(Field::Ext),
+            _ => None,
+        }
+    }
+
+    fn is_numeric(&self) -> bool {
+        match self {
+            Field::Index | Field::Entry | Field::EntryCount | Field::Layer | Field::LayerCount => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Literal(String),
+    /// A field and the width to zero-pad it to
+    Field(Field, usize),
+}
+
+/// A pattern for the path of each exported file, relative to the export directory. Fields are
+/// written in braces, and numeric fields can be zero-padded with a width, as in `{index:04}`.
+/// Literal braces are written `{{` and `}}`.
+///
+/// # Fields
+/// * `{index}` - the row of the BGM sheet
+/// * `{entry}`, `{entry_count}` - the SCD entry and the number of entries in the SCD
+/// * `{layer}`, `{layer_count}` - the 1-based layer and the number of layers in the entry
+/// * `{scd_path}` - the SCD path without its leading `music` folder and extension, e.g.
+/// `ffxiv/BGM_Field_Gri_01`
+/// * `{scd_folder}`, `{scd_name}` - the folder and file name parts of `{scd_path}`
+/// * `{expansion}` - the expansion the SCD folder belongs to, e.g. `Heavensward`
+/// * `{title}` - the sheet-derived title, such as the Orchestrion name, or else `{scd_name}`
+/// * `{name}` - the name chosen by `ExportOptions::naming`, e.g. `ffxiv/Serenity`
+/// * `{_entry}`, `{_layer}` - `_entry<n>` and `_layer<n>`, only for SCDs with more than one entry
+/// and entries with more than one layer respectively
+/// * `{_part}` - `_intro` or `_loop` for files split by `ExportOptions::split_intro`
+/// * `{ext}` - the file extension including its dot, e.g. `.ogg`
+///
+/// `{_part}` and `{ext}` are added at the end when missing, so split files and sidecars never
+/// share a name with the audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathTemplate {
+    tokens: Vec<Token>,
+}
+
+impl Default for PathTemplate {
+    /// `{name}{_entry}{_layer}{_part}{ext}`, which mirrors the layout of the sqpack.
+    fn default() -> PathTemplate {
+        PathTemplate::parse("{name}{_entry}{_layer}{_part}{ext}").unwrap()
+    }
+}
+
+impl PathTemplate {
+    /// Parses a template, returning `AzureError::InvalidExportSettings` for unknown fields,
+    /// unmatched braces or widths on non-numeric fields.
+    pub fn parse(template: &str) -> Result<PathTemplate, AzureError> {
+        let mut tokens = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut spec = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => spec.push(c),
+                            None => return Err(AzureError::InvalidExportSettings("Unclosed field in path template")),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        tokens.push(Token::Literal(literal.split_off(0)));
+                    }
+                    tokens.push(parse_field(&spec)?);
+                },
+                '}' => return Err(AzureError::InvalidExportSettings("Unmatched '}' in path template")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+
+        let has_field = |tokens: &Vec<Token>, field: Field| tokens.iter().any(|token| *token == Token::Field(field, 0));
+        if !has_field(&tokens, Field::Ext) {
+            tokens.push(Token::Field(Field::Ext, 0));
+        }
+        if !has_field(&tokens, Field::PartSuffix) {
+            let ext_position = tokens.iter().position(|token| *token == Token::Field(Field::Ext, 0)).unwrap();
+            tokens.insert(ext_position, Token::Field(Field::PartSuffix, 0));
+        }
+        Ok(PathTemplate { tokens })
+    }
+
+    /// Renders the path of one exported file. `part` is the `{_part}` suffix without its
+    /// underscore and `extension` is without its dot.
+    pub fn render(&self, source: &ExportSource, layer: usize, layer_count: usize, part: Option<&str>, extension: &str) -> String {
+        let scd_path = scd_base_path(&source.scd_path);
+        let (scd_folder, scd_name) = match scd_path.rfind('/') {
+            Some(folder_end) => (&scd_path[..folder_end], &scd_path[folder_end + 1..]),
+            None => ("", scd_path),
+        };
+
+        self.tokens.iter().map(|token| match token {
+            Token::Literal(literal) => literal.clone(),
+            Token::Field(field, width) => {
+                let number = |value: usize| format!("{:01$}", value, width);
+                match field {
+                    Field::Index => number(source.bgm_index),
+                    Field::Entry => number(source.entry_index),
+                    Field::EntryCount => number(source.entry_count),
+                    Field::Layer => number(layer),
+                    Field::LayerCount => number(layer_count),
+                    Field::ScdPath => scd_path.to_string(),
+                    Field::ScdFolder => scd_folder.to_string(),
+                    Field::ScdName => scd_name.to_string(),
+                    Field::Expansion => expansion_name(scd_folder).unwrap_or(scd_folder).to_string(),
+                    Field::Title => source.title.as_ref().map_or(scd_name.to_string(), |title| sanitize_component(title)),
+                    Field::Name => source.base_path.clone(),
+                    Field::EntrySuffix if source.entry_count > 1 => format!("_entry{}", source.entry_index),
+                    Field::LayerSuffix if layer_count > 1 => format!("_layer{}", layer),
+                    Field::PartSuffix => part.map_or(String::new(), |part| format!("_{}", part)),
+                    Field::Ext => format!(".{}", extension),
+                    _ => String::new(),
+                }
+            },
+        }).collect()
+    }
+}
+
+fn parse_field(spec: &str) -> Result<Token, AzureError> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("");
+    let field = Field::from_name(name)
+        .ok_or(AzureError::InvalidExportSettings("Unknown field in path template"))?;
+    match parts.next() {
+        None => Ok(Token::Field(field, 0)),
+        Some(width) => {
+            if !field.is_numeric() {
+                return Err(AzureError::InvalidExportSettings("Only numeric path template fields can be padded"));
+            }
+            if !width.starts_with('0') {
+                return Err(AzureError::InvalidExportSettings("Path template widths must start with 0, as in {index:04}"));
+            }
+            width.parse::<usize>()
+                .map(|width| Token::Field(field, width))
+                .map_err(|_| AzureError::InvalidExportSettings("Invalid width in path template"))
+        },
+    }
+}
+
+#[cfg(test)]
+mod template_tests {
+    use super::*;
+    use ::sha1::Sha1;
+
+    fn source(entry_count: usize, title: Option<&str>) -> ExportSource {
+        ExportSource {
+            scd_path: "music/ex1/BGM_EX1_Field_Cwh_01.scd".into(),
+            base_path: "ex1/BGM_EX1_Field_Cwh_01".into(),
+            bgm_index: 7,
+            entry_index: 1,
+            entry_count,
+            title: title.map(|title| title.into()),
+            sha1: Sha1::new().digest(),
+        }
+    }
+
+    #[test]
+    fn default_layout() {
+        let template = PathTemplate::default();
+        assert_eq!(template.render(&source(1, None), 1, 1, None, "ogg"), "ex1/BGM_EX1_Field_Cwh_01.ogg");
+        assert_eq!(template.render(&source(2, None), 2, 3, Some("loop"), "flac"),
+                   "ex1/BGM_EX1_Field_Cwh_01_entry1_layer2_loop.flac");
+    }
+
+    #[test]
+    fn fields() {
+        let template = PathTemplate::parse("{expansion}/{index:04}_{title}{_layer}{ext}").unwrap();
+        assert_eq!(template.render(&source(1, Some("Heavensward")), 2, 2, None, "mp3"),
+                   "Heavensward/0007_Heavensward_layer2.mp3");
+        assert_eq!(template.render(&source(1, None), 1, 1, Some("intro"), "json"),
+                   "Heavensward/0007_BGM_EX1_Field_Cwh_01_intro.json");
+        let template = PathTemplate::parse("{{{scd_folder}}}/{scd_name}").unwrap();
+        assert_eq!(template.render(&source(1, None), 1, 1, None, "wav"), "{ex1}/BGM_EX1_Field_Cwh_01.wav");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(PathTemplate::parse("{index").is_err());
+        assert!(PathTemplate::parse("index}").is_err());
+        assert!(PathTemplate::parse("{album}").is_err());
+        assert!(PathTemplate::parse("{title:04}").is_err());
+        assert!(PathTemplate::parse("{index:4}").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! A RIFF/WAVE writer for 16-bit interleaved PCM, optionally carrying a `smpl` chunk so samplers
+//! and game engines can loop the audio natively.
+
+use std::io::{Seek, SeekFrom, Write};
+use ::errors::AzureError;
+
+const BITS_PER_SAMPLE: u16 = 16;
+/// The size of the largest chunk written after the audio, a `smpl` chunk with its header.
+const MAX_TRAILER_LEN: u64 = 8 + 60;
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(id);
+    push_u32(out, body.len() as u32);
+    out.extend_from_slice(body);
+    if body.len() % 2 != 0 {
+        out.push(0);
+    }
+}
+
+fn fmt_chunk(channels: u16, sample_rate: u32) -> Vec<u8> {
+    let block_align = channels * BITS_PER_SAMPLE / 8;
+    let mut body = Vec::with_capacity(16);
+    push_u16(&mut body, 1);
+    push_u16(&mut body, channels);
+    push_u32(&mut body, sample_rate);
+    push_u32(&mut body, sample_rate * block_align as u32);
+    push_u16(&mut body, block_align);
+    push_u16(&mut body, BITS_PER_SAMPLE);
+    body
+}
+
+/// `loop_start` and `loop_end` are in sample frames, with `loop_end` exclusive as in the SCD
+/// comments. The `smpl` chunk stores the end inclusively.
+fn smpl_chunk(sample_rate: u32, loop_start: u32, loop_end: u32) -> Vec<u8> {
+    let mut body = Vec::with_capacity(60);
+    // manufacturer, product
+    push_u32(&mut body, 0);
+    push_u32(&mut body, 0);
+    // sample period in nanoseconds
+    push_u32(&mut body, 1_000_000_000 / sample_rate);
+    // MIDI unity note (middle C), pitch fraction
+    push_u32(&mut body, 60);
+    push_u32(&mut body, 0);
+    // SMPTE format, SMPTE offset
+    push_u32(&mut body, 0);
+    push_u32(&mut body, 0);
+    // one sample loop, no sampler specific data
+    push_u32(&mut body, 1);
+    push_u32(&mut body, 0);
+    // cue point id, forward loop, start, end, fraction, play count (0 is infinite)
+    push_u32(&mut body, 0);
+    push_u32(&mut body, 0);
+    push_u32(&mut body, loop_start);
+    push_u32(&mut body, loop_end - 1);
+    push_u32(&mut body, 0);
+    push_u32(&mut body, 0);
+    body
+}
+
+/// Fails if a file with a header of `header_len` bytes and `data_len` bytes of audio could overflow
+/// the 32-bit RIFF chunk sizes.
+fn check_size(header_len: usize, data_len: usize) -> Result<(), AzureError> {
+    if header_len as u64 - 8 + data_len as u64 + MAX_TRAILER_LEN > u32::max_value() as u64 {
+        Err(AzureError::ErrorExporting("WAV files cannot hold more than 4 GiB of audio"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Encodes chunks of interleaved 16-bit samples into a WAV file written to `out`. `loop_points`
+/// is a `(start, end)` pair of sample frame offsets; it is written as a `smpl` chunk if it lies
+/// within the audio. The chunk sizes are filled in once every sample has been written, and audio
+/// that would not fit them is an error rather than a truncated file.
+pub fn encode<W, I>(out: &mut W, samples: I, channels: usize, sample_rate: u32, loop_points: Option<(usize, usize)>) -> Result<(), AzureError>
+    where W: Write + Seek, I: Iterator<Item = Result<Vec<i16>, AzureError>> {
+    let write_err = |_| AzureError::ErrorExporting("Writing WAV");
+    let start = out.seek(SeekFrom::Current(0)).map_err(write_err)?;
+    let mut header = b"RIFF\0\0\0\0WAVE".to_vec();
+    push_chunk(&mut header, b"fmt ", &fmt_chunk(channels as u16, sample_rate));
+    header.extend_from_slice(b"data\0\0\0\0");
+    out.write_all(&header).map_err(write_err)?;
+
+    let mut data_len = 0usize;
+    for chunk in samples {
+        let chunk = chunk?;
+        let mut pcm = Vec::with_capacity(chunk.len() * 2);
+        chunk.iter().for_each(|s| push_u16(&mut pcm, *s as u16));
+        check_size(header.len(), data_len + pcm.len())?;
+        out.write_all(&pcm).map_err(write_err)?;
+        data_len += pcm.len();
+    }
+
+    let frames = data_len / 2 / channels;
+    let mut trailer = Vec::new();
+    if let Some((start, end)) = loop_points {
+        if start < end && end <= frames {
+            push_chunk(&mut trailer, b"smpl", &smpl_chunk(sample_rate, start as u32, end as u32));
+        }
+    }
+    out.write_all(&trailer).map_err(write_err)?;
+
+    let mut riff_len = Vec::with_capacity(4);
+    push_u32(&mut riff_len, (header.len() - 8 + data_len + trailer.len()) as u32);
+    let mut data_len_bytes = Vec::with_capacity(4);
+    push_u32(&mut data_len_bytes, data_len as u32);
+    out.seek(SeekFrom::Start(start + 4))
+        .and_then(|_| out.write_all(&riff_len))
+        .and_then(|_| out.seek(SeekFrom::Start(start + header.len() as u64 - 4)))
+        .and_then(|_| out.write_all(&data_len_bytes))
+        .and_then(|_| out.seek(SeekFrom::End(0)))
+        .map(|_| ())
+        .map_err(write_err)
+}
+
+#[cfg(test)]
+mod wav_tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn encode_all(data: &[i16], loop_points: Option<(usize, usize)>) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        encode(&mut out, vec![Ok(data.to_vec())].into_iter(), 2, 44100, loop_points).unwrap();
+        out.into_inner()
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        bytes[at] as u32 | (bytes[at + 1] as u32) << 8 | (bytes[at + 2] as u32) << 16 | (bytes[at + 3] as u32) << 24
+    }
+
+    #[test]
+    fn layout_with_loop() {
+        let data = vec![1i16, -1, 2, -2, 3, -3, 4, -4];
+        let wav = encode_all(&data, Some((1, 3)));
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(read_u32(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        // data chunk follows the 16 byte fmt chunk
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(read_u32(&wav, 40), 16);
+        assert_eq!(&wav[44..48], &[1, 0, 0xFF, 0xFF]);
+        assert_eq!(&wav[60..64], b"smpl");
+        assert_eq!(read_u32(&wav, 64), 60);
+        // loop start and inclusive loop end
+        assert_eq!(read_u32(&wav, 68 + 44), 1);
+        assert_eq!(read_u32(&wav, 68 + 48), 2);
+    }
+
+    #[test]
+    fn size_limit() {
+        let header_len = 36 + 8;
+        assert!(check_size(header_len, 1 << 31).is_ok());
+        assert!(check_size(header_len, u32::max_value() as usize - 36 - 68).is_ok());
+        assert!(check_size(header_len, u32::max_value() as usize - 36 - 67).is_err());
+    }
+
+    #[test]
+    fn out_of_range_loop_is_dropped() {
+        let data = vec![0i16; 8];
+        let wav = encode_all(&data, Some((1, 5)));
+        assert_eq!(wav.len(), 44 + 16);
+    }
+}
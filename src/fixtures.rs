@@ -0,0 +1,264 @@
+//! Builds SCD files in memory, so that decoding and exporting can be tested without game data.
+//! Available to the crate's own tests, and to others through the `fixtures` feature. Tests under
+//! `tests/` build against the library without `cfg(test)`, so they need the feature too:
+//! `cargo test --features fixtures`.
+
+use std::f64::consts::PI;
+
+use ::errors::AzureError;
+use ::exporting::VorbisRetagger;
+use ::exporting::msadpcm::Channel;
+use ::scd::{MsAdpcm, ScdEntry};
+
+extern crate vorbis;
+
+use self::vorbis::{Encoder, VorbisQuality};
+
+/// The byte the Vorbis headers of `scd` are XOR-ed with
+const SCD_XOR: u8 = 0x5A;
+/// The standard MSADPCM predictor coefficients
+const ADPCM_COEFFICIENTS: [(i16, i16); 7] = [(256, 0), (512, -256), (0, 0), (192, 64), (240, 0), (460, -208), (392, -232)];
+/// The coefficients every block is encoded with
+const ADPCM_PREDICTOR: usize = 1;
+const ADPCM_CODEC: usize = 0x0C;
+/// The length of a WAVEFORMATEX with the MSADPCM extension and its 7 coefficient pairs
+const ADPCM_FORMAT_LEN: usize = 0x32;
+
+/// How the audio of a fixture entry is stored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixtureCodec {
+    Vorbis,
+    MsAdpcm,
+}
+
+/// One entry of a synthetic SCD, of the given layout and length.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FixtureEntry {
+    pub channels: u8,
+    pub rate: u64,
+    pub frames: usize,
+    /// Written as the `LoopStart` and `LoopEnd` comments of Vorbis entries and in the entry header
+    /// of MSADPCM entries, in frames
+    pub loop_points: Option<(usize, usize)>,
+    pub codec: FixtureCodec,
+}
+
+impl FixtureEntry {
+    pub fn new(channels: u8, rate: u64, frames: usize) -> FixtureEntry {
+        FixtureEntry { channels, rate, frames, loop_points: None, codec: FixtureCodec::Vorbis }
+    }
+
+    /// Sets the loop points, in frames.
+    pub fn looped(self, start: usize, end: usize) -> FixtureEntry {
+        FixtureEntry { loop_points: Some((start, end)), ..self }
+    }
+
+    /// Stores the entry as MSADPCM rather than Ogg Vorbis.
+    pub fn msadpcm(self) -> FixtureEntry {
+        FixtureEntry { codec: FixtureCodec::MsAdpcm, ..self }
+    }
+
+    /// The interleaved samples the entry encodes. Both channels of layer `n` (counting from 0)
+    /// carry a sine of `220 * (n + 1)` Hz at amplitude `8000 / (n + 1)`, so layers can be told
+    /// apart once decoded.
+    pub fn samples(&self) -> Vec<i16> {
+        let channels = self.channels as usize;
+        (0..self.frames * channels).map(|sample| {
+            let (frame, layer) = (sample / channels, (sample % channels) / 2 + 1);
+            let phase = 2.0 * PI * 220.0 * layer as f64 * frame as f64 / self.rate as f64;
+            (phase.sin() * 8000.0 / layer as f64) as i16
+        }).collect()
+    }
+
+    /// Encodes the entry as an Ogg Vorbis stream.
+    pub fn ogg_vorbis(&self) -> Result<Vec<u8>, AzureError> {
+        let encode_err = |_| AzureError::ErrorExporting("Encoding fixture");
+        let mut encoder = Encoder::new(self.channels, self.rate, VorbisQuality::Quality).map_err(encode_err)?;
+        let mut encoded = encoder.encode(&self.samples()).map_err(encode_err)?;
+        encoded.extend(encoder.flush().map_err(encode_err)?);
+
+        let comments = self.loop_points.map_or_else(Vec::new, |(start, end)| {
+            vec![("LoopStart", start.to_string()), ("LoopEnd", end.to_string())]
+        });
+        let mut ogg = Vec::new();
+        VorbisRetagger::new(&mut ogg, comments).write(&encoded)?;
+        Ok(ogg)
+    }
+
+    /// Encodes the entry as MSADPCM, in blocks of 256 bytes per channel. The last block is padded
+    /// to two frames and to a whole byte, so the audio may be a frame longer than `frames`.
+    pub fn msadpcm_audio(&self) -> MsAdpcm {
+        let channels = self.channels as usize;
+        let block_align = 256 * channels;
+        let frames_per_block = (block_align - 7 * channels) * 2 / channels + 2;
+        let mut data = Vec::new();
+        for block in self.samples().chunks(frames_per_block * channels) {
+            encode_adpcm_block(block, channels, &mut data);
+        }
+        MsAdpcm {
+            channels,
+            rate: self.rate,
+            block_align,
+            coefficients: ADPCM_COEFFICIENTS.to_vec(),
+            loop_points: self.loop_points,
+            data,
+        }
+    }
+
+    /// Encodes the entry in its codec.
+    pub fn entry(&self) -> Result<ScdEntry, AzureError> {
+        match self.codec {
+            FixtureCodec::Vorbis => self.ogg_vorbis().map(ScdEntry::Ogg),
+            FixtureCodec::MsAdpcm => Ok(ScdEntry::MsAdpcm(self.msadpcm_audio())),
+        }
+    }
+}
+
+fn encode_adpcm_block(block: &[i16], channels: usize, out: &mut Vec<u8>) {
+    let (coefficient1, coefficient2) = ADPCM_COEFFICIENTS[ADPCM_PREDICTOR];
+    // a block of a single frame repeats it as both header samples
+    let header_sample = |frame: usize, channel: usize| block[(frame * channels).min(block.len() - channels) + channel] as i32;
+    let mut states = (0..channels)
+        .map(|channel| Channel {
+            coefficients: (coefficient1 as i32, coefficient2 as i32),
+            delta: 16,
+            sample1: header_sample(1, channel),
+            sample2: header_sample(0, channel),
+        })
+        .collect::<Vec<_>>();
+    out.extend((0..channels).map(|_| ADPCM_PREDICTOR as u8));
+    states.iter().for_each(|state| push_u16(out, state.delta as usize));
+    states.iter().for_each(|state| push_u16(out, state.sample1 as usize));
+    states.iter().for_each(|state| push_u16(out, state.sample2 as usize));
+
+    let mut nibbles = block.iter().enumerate().skip(channels * 2)
+        .map(|(i, sample)| states[i % channels].encode(*sample))
+        .collect::<Vec<u8>>();
+    if nibbles.len() % 2 != 0 {
+        nibbles.push(0);
+    }
+    out.extend(nibbles.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+}
+
+/// Converts a frame offset into MSADPCM audio to the byte offset SCD entry headers store loop
+/// points as. Frames within a block's header round up to its first encoded frame.
+fn adpcm_frames_to_bytes(adpcm: &MsAdpcm, frames: usize) -> usize {
+    let (block, frame) = (frames / adpcm.frames_per_block(), frames % adpcm.frames_per_block());
+    let within = if frame == 0 { 0 } else { adpcm.block_header_len() + ((frame - 2) * adpcm.channels + 1) / 2 };
+    block * adpcm.block_align + within
+}
+
+/// Builds an SCD holding the given entries. The headers of Vorbis entries are XOR-ed with a
+/// single byte, as in older game files.
+pub fn scd(entries: &[FixtureEntry]) -> Result<Vec<u8>, AzureError> {
+    let entries = entries.iter().map(|entry| entry.entry()).collect::<Result<Vec<_>, _>>()?;
+    Ok(wrap(&entries, SCD_XOR))
+}
+
+fn push_u16(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8]);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+}
+
+/// The length of the pages holding the three Vorbis header packets, and the channel count and
+/// sample rate from the identification header.
+fn vorbis_header(ogg: &[u8]) -> (usize, usize, usize) {
+    let (mut offset, mut packets) = (0, 0);
+    let ident = 27 + ogg[26] as usize;
+    while packets < 3 && offset + 27 <= ogg.len() {
+        let lacing = &ogg[offset + 27..offset + 27 + ogg[offset + 26] as usize];
+        packets += lacing.iter().filter(|len| **len < 255).count();
+        offset += 27 + lacing.len() + lacing.iter().map(|len| *len as usize).sum::<usize>();
+    }
+    let rate = ogg[ident + 12..ident + 16].iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as usize);
+    (offset, ogg[ident + 11] as usize, rate)
+}
+
+/// Wraps audio as the entries of an SCD, XOR-ing the Vorbis headers of Ogg entries with `xor`
+/// unless it is 0.
+pub fn wrap(entries: &[ScdEntry], xor: u8) -> Vec<u8> {
+    let entry_table = 0x50;
+    let first_entry = entry_table + (entries.len() * 4 + 0x0F) / 0x10 * 0x10;
+
+    let mut data = Vec::new();
+    let mut entry_offsets = Vec::new();
+    for entry in entries {
+        entry_offsets.push(first_entry + data.len());
+        match entry {
+            ScdEntry::Ogg(ogg) => push_ogg_entry(&mut data, ogg, xor),
+            ScdEntry::MsAdpcm(adpcm) => push_adpcm_entry(&mut data, adpcm),
+        }
+    }
+
+    let mut out = b"SEDBSSCF".to_vec();
+    push_u32(&mut out, 3);
+    out.extend_from_slice(&[0, 4]);
+    push_u16(&mut out, 0x30);
+    push_u32(&mut out, first_entry + data.len());
+    out.resize(0x30, 0);
+    push_u16(&mut out, 0);
+    push_u16(&mut out, 0);
+    push_u16(&mut out, entries.len());
+    push_u16(&mut out, 0);
+    push_u32(&mut out, 0);
+    push_u32(&mut out, entry_table);
+    out.resize(entry_table, 0);
+    entry_offsets.into_iter().for_each(|offset| push_u32(&mut out, offset));
+    out.resize(first_entry, 0);
+    out.extend(data);
+    out
+}
+
+fn push_ogg_entry(out: &mut Vec<u8>, ogg: &[u8], xor: u8) {
+    let seek_table = [0u8; 4];
+    let (header_len, channels, rate) = vorbis_header(ogg);
+    push_u32(out, ogg.len() - header_len);
+    push_u32(out, channels);
+    push_u32(out, rate);
+    push_u32(out, 0x06);
+    push_u32(out, 0);
+    push_u32(out, 0);
+    push_u32(out, 0x20 + seek_table.len() + header_len);
+    push_u32(out, 0);
+
+    out.extend_from_slice(&[2, 0x20, xor, 0]);
+    out.extend_from_slice(&[0; 12]);
+    push_u32(out, seek_table.len());
+    push_u32(out, header_len);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&seek_table);
+    out.extend(ogg[..header_len].iter().map(|byte| byte ^ xor));
+    out.extend_from_slice(&ogg[header_len..]);
+}
+
+fn push_adpcm_entry(out: &mut Vec<u8>, adpcm: &MsAdpcm) {
+    let (loop_start, loop_end) = adpcm.loop_points
+        .map_or((0, 0), |(start, end)| (adpcm_frames_to_bytes(adpcm, start), adpcm_frames_to_bytes(adpcm, end)));
+    push_u32(out, adpcm.data.len());
+    push_u32(out, adpcm.channels);
+    push_u32(out, adpcm.rate as usize);
+    push_u32(out, ADPCM_CODEC);
+    push_u32(out, loop_start);
+    push_u32(out, loop_end);
+    push_u32(out, ADPCM_FORMAT_LEN);
+    push_u32(out, 0);
+
+    // WAVEFORMATEX, then the samples per block and the coefficients
+    push_u16(out, 0x02);
+    push_u16(out, adpcm.channels);
+    push_u32(out, adpcm.rate as usize);
+    push_u32(out, adpcm.rate as usize * adpcm.block_align / adpcm.frames_per_block());
+    push_u16(out, adpcm.block_align);
+    push_u16(out, 4);
+    push_u16(out, ADPCM_FORMAT_LEN - 0x12);
+    push_u16(out, adpcm.frames_per_block());
+    push_u16(out, adpcm.coefficients.len());
+    for (coefficient1, coefficient2) in &adpcm.coefficients {
+        push_u16(out, *coefficient1 as u16 as usize);
+        push_u16(out, *coefficient2 as u16 as usize);
+    }
+    out.extend_from_slice(&adpcm.data);
+}
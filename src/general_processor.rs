@@ -1,14 +1,17 @@
+use std::cmp::Reverse;
 use std::collections::{BTreeMap, HashMap};
-use std::fs::DirBuilder;
+use std::path::Path;
+use std::fs::{DirBuilder, File};
 
-use ::{BGMOptions, AzureOptions};
+use ::{BGMOptions, AzureOptions, OverwritePolicy};
 use ::errors::AzureError;
-use ::async_data_processor::{ThreadStatus, async_processor};
-use ::sqpack_blue::{ExFileIdentifier, FFXIVError};
-use ::sqpack_blue::sheet::ex::SheetLanguage;
+use ::async_data_processor::{ThreadStatus, PauseEvent, PauseTracker, async_processor};
+use ::atomic_write::write_atomic;
 use ::sha1::Sha1;
 use ::manifest::*;
 use ::callbacks::*;
+use ::exporting::{ExportSource, ExportRun, OutputNaming, OutputPaths, WorkOrder, scd_base_path};
+use ::journal::Journal;
 
 fn is_known_skip(skip: &str) -> bool {
     match skip {
@@ -21,6 +24,47 @@ fn is_known_skip(skip: &str) -> bool {
     }
 }
 
+/// Records the source hash of every track exported into a directory, so that
+/// `OverwritePolicy::IfSourceChanged` can tell whether a track's existing files are stale.
+const SOURCE_RECORD_FILE: &str = ".azureost-sources.json";
+
+/// Reads the source record of an export directory. A missing or unreadable record is treated as
+/// empty, so every track counts as changed.
+fn read_source_record(path: &Path) -> ManifestFile {
+    File::open(path).ok()
+        .and_then(|file| ::serde_json::from_reader::<File, ManifestFile>(file).ok())
+        .unwrap_or_else(|| ManifestFile { files: BTreeMap::new(), loose_files: BTreeMap::new() })
+}
+
+/// Finds a track in an earlier manifest. Tracks from sources that key them by path are matched on
+/// their path, as their indexes are only positions.
+fn previous_track<'a>(manifest: &'a ManifestFile, track: &TrackManifest, by_path: bool) -> Option<&'a TrackManifest> {
+    if by_path {
+        manifest.loose_files.get(&track.name)
+    } else {
+        manifest.files.get(&track.index)
+    }
+}
+
+/// Adds tracks to a manifest, keyed as `previous_track` looks them up.
+fn insert_tracks<I: Iterator<Item=TrackManifest>>(manifest: &mut ManifestFile, tracks: I, by_path: bool) {
+    if by_path {
+        manifest.loose_files.extend(tracks.map(|t_mf| (t_mf.name.clone(), t_mf)));
+    } else {
+        manifest.files.extend(tracks.map(|t_mf| (t_mf.index, t_mf)));
+    }
+}
+
+/// Passes a change in the paused state of the workers on to the callbacks.
+fn notify_pause(callbacks: &AzureCallbacks, event: Option<PauseEvent>, total_operations_count: usize, operations_progress: usize) {
+    let info = AzureProcessPause { total_operations_count, operations_progress };
+    match event {
+        Some(PauseEvent::Paused) => callbacks.process_paused(info),
+        Some(PauseEvent::Resumed) => callbacks.process_resumed(info),
+        None => {},
+    }
+}
+
 //fn get_sheet_index(ffxiv: FFXIV) ->
 pub fn process(azure_opts: AzureOptions,
                bgm_opts: BGMOptions,
@@ -30,87 +74,69 @@ pub fn process(azure_opts: AzureOptions,
     callbacks.pre_phase(AzureProcessPhase::Begin);
     callbacks.post_phase(AzureProcessPhase::Begin);
 
-    Ok(azure_opts.ffxiv.clone())
-        // get the Sheet index
-        .and_then(|ffxiv| {
+    let by_path = azure_opts.source.keys_by_path();
+
+    Ok(azure_opts.source.clone())
+        // Read the BGM Sheet
+        .and_then(|source| {
             callbacks.pre_phase(AzureProcessPhase::ReadingBGMSheet);
-            ffxiv.get_sheet_index().map_err(|e| e.into())
-                .map(|a| (ffxiv, a))
-        })
-        // Get the BGM Sheet using the sheet index
-        .and_then(|(ffxiv, sheet_index)| {
-            ffxiv.get_sheet(&String::from("bgm"),
-                            SheetLanguage::None, &sheet_index)
-                .map_err(|e| e.into())
-                .map(|a| (ffxiv, a))
+            source.bgm_paths().map(|paths| (source, paths))
         })
-        // Read the BGM Sheet to transform the requested indices into ExFileIdentifiers
-        .and_then(|(ffxiv, sheet)| {
+        // Transform the requested indices into SCD paths
+        .and_then(|(source, paths)| {
             let invalid_indices = process_indicies.iter().cloned().filter(|index| {
-                !sheet.rows.contains_key(index)
+                !paths.contains_key(index)
             }).collect::<Vec<_>>();
             if invalid_indices.len() > 0 {
                 Err(AzureError::InvalidBGMIndex(invalid_indices))
             } else {
-                // TODO refactor into functions
-                let (exfiles, errors): (Vec<_>, Vec<_>) =
+                let (exfiles, invalid_paths): (Vec<_>, Vec<_>) =
                     process_indicies.iter().cloned()
-                        .map(|index| { (index, &sheet.rows[&index]) })
-                        .map(|(index, row)| {
-                            row.read_cell_data::<String>(0).map_err(|e| {
-                                FFXIVError::SheetError(e)
-                            }).map(|f_str| {
-                                (index, f_str)
-                            })
-                        })
-                        .filter(|s| {
-                            match s {
-                                Ok(s) => !is_known_skip(s.1.as_str()),
-                                Err(_) => true
-                            }
-                        })
-                        .map(|s| {
-                            s.and_then(|(index, f_str)| {
-                                ExFileIdentifier::new(&f_str).map(|exf| (index, exf))
-                            }).and_then(|e| Ok(e))
-                        })
-                        .partition(|e| e.is_ok());
-                //.partition::<Vec<Result<(usize, ExFileIdentifier), FFXIVError>>, Vec<_>>(|e| {Result::is_ok});
-                let exfiles = exfiles.into_iter().map(|k| k.unwrap()).collect::<Vec<_>>();
-                let errors = errors.into_iter().map(|k| k.err().unwrap()).collect::<Vec<_>>();
+                        .map(|index| (index, paths[&index].clone()))
+                        .filter(|(_, path)| !is_known_skip(path.as_str()))
+                        .partition(|(_, path)| source.is_valid_path(path));
 
                 callbacks.post_phase(AzureProcessPhase::ReadingBGMSheet);
 
-                if !errors.is_empty() {
-                    Err(AzureError::FFXIVErrorVec(errors))
+                if !invalid_paths.is_empty() {
+                    Err(AzureError::InvalidScdPaths(invalid_paths))
                 } else {
-                    Ok((ffxiv, exfiles))
+                    Ok((source, exfiles))
                 }
             }
         })
         // hash the exfile SCDs
-        .and_then(|(ffxiv, exfiles)| {
-            let hashes =
-                if bgm_opts.compare_file.is_some() || bgm_opts.save_file.is_some() {
+        .and_then(|(source, exfiles)| {
+            let (hashes, sizes) =
+                if bgm_opts.compare_file.is_some() || bgm_opts.save_file.is_some()
+                    || bgm_opts.overwrite == OverwritePolicy::IfSourceChanged
+                    || bgm_opts.export_options.journal.is_some()
+                    || (bgm_opts.export_mode.is_some() && bgm_opts.export_options.work_order == WorkOrder::LargestFirst) {
                     callbacks.pre_phase(AzureProcessPhase::Hashing);
                     callbacks.process_begin(AzureProcessBegin {
                         total_operations_count: exfiles.len()
                     });
                     let recv = async_processor(
                         azure_opts.thread_count,
-                        ffxiv.clone(),
+                        source.clone(),
                         &exfiles,
+                        &azure_opts.cancel,
+                        &azure_opts.pause,
                         |index, data| {
-                            ThreadStatus::Continue((index, Sha1::from(data).digest()))
+                            ThreadStatus::Continue((index, Sha1::from(&data).digest(), data.len()))
                         });
                     let mut hashes = HashMap::new();
+                    let mut sizes = HashMap::new();
                     let mut threads_completed = 0usize;
                     let mut files_completed = 0usize;
                     let mut files_errored = 0usize;
+                    let mut pause_tracker = PauseTracker::new(azure_opts.thread_count);
                     'thread_recv: for received in recv {
+                        notify_pause(callbacks, pause_tracker.update(&received), exfiles.len(), files_completed);
                         match received {
-                            ThreadStatus::Continue((index, digest)) => {
+                            ThreadStatus::Continue((index, digest, size)) => {
                                 hashes.insert(index, digest);
+                                sizes.insert(index, size);
                                 files_completed += 1;
                                 callbacks.process_progress(AzureProcessProgress {
                                     total_operations_count: exfiles.len(),
@@ -135,6 +161,7 @@ pub fn process(azure_opts: AzureOptions,
                                     reason, current_operation
                                 });
                             },
+                            ThreadStatus::Paused | ThreadStatus::Resumed => {},
                         }
                     }
                     callbacks.process_complete(AzureProcessComplete {
@@ -142,29 +169,34 @@ pub fn process(azure_opts: AzureOptions,
                         operations_errored: files_errored
                     });
                     callbacks.post_phase(AzureProcessPhase::Hashing);
-                    Some(hashes)
+                    (Some(hashes), sizes)
                 } else {
-                    None
+                    (None, HashMap::new())
                 };
 
-            Ok((ffxiv, exfiles, hashes))
+            // a cancelled hashing phase leaves hashes missing, so stop before they are used
+            if azure_opts.cancel.is_cancelled() {
+                return Err(AzureError::Cancelled);
+            }
+            Ok((source, exfiles, hashes, sizes))
         })
         // partition exfiles into collected and uncollected
-        .and_then(|(ffxiv, exfiles, hashes)| {
+        .and_then(|(source, exfiles, hashes, sizes)| {
             callbacks.pre_phase(AzureProcessPhase::Collecting);
             let collects: (Vec<TrackManifest>, Vec<TrackManifest>) =
                 exfiles.into_iter()
-                    .map(|(index, exf)| {
-                        TrackManifest {
-                            index,
-                            name: exf.get_exfile_string().clone(),
-                            sha1: hashes.as_ref().map(|h| h[&index]).unwrap_or_else(|| Sha1::new().digest()),
-                        }
+                    // tracks that could not be read while hashing were reported then, and are left out
+                    .filter_map(|(index, name)| {
+                        let sha1 = match hashes {
+                            Some(ref hashes) => *hashes.get(&index)?,
+                            None => Sha1::new().digest(),
+                        };
+                        Some(TrackManifest { index, name, sha1 })
                     })
                     .partition(|track_mf| {
                         bgm_opts.compare_file.as_ref()
                             .map(|compare| {
-                                compare.files.get(&track_mf.index)
+                                previous_track(compare, track_mf, by_path)
                                     .map(|compare_track_mf| {
                                         compare_track_mf.sha1.ne(&track_mf.sha1)
                                     })
@@ -172,21 +204,21 @@ pub fn process(azure_opts: AzureOptions,
                             }).unwrap_or(true)
                     });
             callbacks.post_phase(AzureProcessPhase::Collecting);
-            Ok((ffxiv, collects))
+            Ok((source, collects, sizes))
         })
         // save manifest file
-        .and_then(|(ffxiv,
-                       (collects, uncollects))| {
+        .and_then(|(source,
+                       (collects, uncollects),
+                       sizes)| {
 
             let next = bgm_opts.save_file.as_ref()
                 .and_then(|save_file| {
                     callbacks.pre_phase(AzureProcessPhase::SavingManifest);
-                    let write_output = Some(::serde_json::to_writer_pretty(save_file,
-                                                        &ManifestFile {
-                                                            files: collects.iter().cloned().chain(uncollects.iter().cloned())
-                                                                .map(|t_mf| (t_mf.index, t_mf))
-                                                                .collect::<BTreeMap<usize, TrackManifest>>()
-                                                        }));
+                    let mut manifest = ManifestFile { files: BTreeMap::new(), loose_files: BTreeMap::new() };
+                    insert_tracks(&mut manifest, collects.iter().cloned().chain(uncollects.iter().cloned()), by_path);
+                    let write_output = Some(::serde_json::to_vec_pretty(&manifest)
+                        .map_err(|_| ())
+                        .and_then(|out| write_atomic(save_file, &out).map_err(|_| ())));
                     callbacks.post_phase(AzureProcessPhase::SavingManifest);
                     write_output
                 })
@@ -194,11 +226,12 @@ pub fn process(azure_opts: AzureOptions,
                     save_res.map_err(|_| AzureError::ErrorWritingSaveFile)
                 })
                 .unwrap_or(Ok(()))
-                .map(|_| (ffxiv, collects, uncollects));
+                .map(|_| (source, collects, uncollects, sizes));
             next
         })
 //        .map(|_| ())
-        .and_then(|(ffxiv, collects, _uncollects)| {
+        .and_then(|(source, collects, _uncollects, sizes)| {
+            let export_options = bgm_opts.export_options.clone();
             let export_result = bgm_opts.export_mode.clone()
                 .and_then(|export_mode| {
                     callbacks.pre_phase(AzureProcessPhase::Exporting);
@@ -208,33 +241,93 @@ pub fn process(azure_opts: AzureOptions,
                             .create(export_mode.get_path())
                             .map_err(|_| AzureError::ErrorExporting("Creating directory"))
                             .and_then(|_| {
-                                collects.iter().map(|t_mf| {
-                                    ffxiv.get_exfile(&t_mf.name)
-                                        .map(|exf| (t_mf.index, exf))
-                                }).collect::<Result<Vec<_>, _>>()
-                                    .and_then(|work| {
+                                match export_options.naming {
+                                    OutputNaming::ScdPath => Ok(HashMap::new()),
+                                    OutputNaming::Orchestrion(language) => source.orchestrion_titles(language),
+                                }
+                            })
+                            .and_then(|titles| {
+                                let source_record_path = export_mode.get_path().join(SOURCE_RECORD_FILE);
+                                let source_record = read_source_record(&source_record_path);
+                                // whether each track may replace files left by an earlier run
+                                let replace_existing = collects.iter().map(|t_mf| {
+                                    let replace = match bgm_opts.overwrite {
+                                        OverwritePolicy::Always => true,
+                                        OverwritePolicy::Never => false,
+                                        OverwritePolicy::IfSourceChanged => previous_track(&source_record, t_mf, by_path)
+                                            .map_or(true, |recorded| recorded.sha1 != t_mf.sha1),
+                                    };
+                                    (t_mf.index, replace)
+                                }).collect::<HashMap<usize, bool>>();
+                                let hashes = collects.iter().map(|t_mf| (t_mf.index, t_mf.sha1)).collect::<HashMap<_, _>>();
+                                let journal = match export_options.journal {
+                                    Some(ref path) => Some(Journal::open(path)?),
+                                    None => None,
+                                };
+                                Ok(collects.iter().map(|t_mf| (t_mf.index, t_mf.name.clone())).collect::<Vec<_>>())
+                                    .and_then(|mut work| {
+                                        if export_options.work_order == WorkOrder::LargestFirst {
+                                            work.sort_by_key(|(index, _)| Reverse(sizes.get(index).cloned().unwrap_or(0)));
+                                        }
                                         let index_name_map = work
-                                            .iter().map(|(index, exf)| (*index, exf.get_exfile_string().clone()))
+                                            .iter().map(|(index, name)| (*index, name.clone()))
                                             .collect::<HashMap<usize, String>>();
+                                        // hold the files of finished layers for them, since with several threads
+                                        // the other tracks may claim paths in a different order than before
+                                        let output_paths = OutputPaths::new();
+                                        if let Some(ref journal) = journal {
+                                            journal.done_files(&hashes).iter().for_each(|file| output_paths.reserve(file));
+                                        }
                                         callbacks.process_begin(AzureProcessBegin{total_operations_count: work.len()});
-                                        let recv = async_processor(azure_opts.thread_count, ffxiv.clone(), &work, move |index, data| {
+                                        let recv = async_processor(azure_opts.thread_count, source.clone(), &work, &azure_opts.cancel, &azure_opts.pause, move |index, data| {
                                             index_name_map.get(&index).map_or(ThreadStatus::Error(format!("Invalid index passed to exporter! Index: {}", index), index), |f_name| {
-                                                let a: Vec<&str> = f_name.split("/").skip(1).collect();
-                                                ffxiv.decode_sound(data)
-                                                    .map_err(|_| AzureError::ErrorDecoding)
-                                                    .and_then(|scd| {
-                                                        let entry_count = scd.header.entry_count as usize;
-                                                        let base_path = a.join("/");
-                                                        scd.entries.into_iter()
+                                                let sha1 = hashes[&index];
+                                                // finished tracks are not decoded again, their files are already reserved
+                                                if journal.as_ref().map_or(false, |journal| journal.is_track_done(index, &sha1)) {
+                                                    return ThreadStatus::Continue((index, true));
+                                                }
+                                                let run = ExportRun {
+                                                    paths: &output_paths,
+                                                    journal: journal.as_ref(),
+                                                    replace_existing: replace_existing[&index],
+                                                };
+                                                let title = titles.get(&f_name.to_lowercase()).cloned();
+                                                source.decode_scd(data)
+                                                    .and_then(|entries| {
+                                                        let entry_count = entries.len();
+                                                        // drop the leading music folder, as `{scd_path}` does
+                                                        let scd_path = scd_base_path(f_name);
+                                                        // keep the folder of the SCD, swapping only the file name for the title
+                                                        let base_path = match title {
+                                                            Some(ref title) => {
+                                                                let title = title.replace('/', "_").replace('\\', "_");
+                                                                match scd_path.rfind('/') {
+                                                                    Some(folder_end) => format!("{}/{}", &scd_path[..folder_end], title),
+                                                                    None => title,
+                                                                }
+                                                            },
+                                                            None => scd_path.to_string(),
+                                                        };
+                                                        entries.into_iter()
                                                             .rev()
                                                             .enumerate()
-                                                            .map(|(index, entry)| {
-                                                                let mut decoded_ogg = Vec::new();
-                                                                decoded_ogg.clone_from(entry.decoded());
-                                                                export_mode.export_file(base_path.as_str(), index, entry_count, decoded_ogg)
+                                                            .map(|(entry_index, entry)| {
+                                                                let source = ExportSource {
+                                                                    scd_path: f_name.clone(),
+                                                                    base_path: base_path.clone(),
+                                                                    bgm_index: index,
+                                                                    entry_index,
+                                                                    entry_count,
+                                                                    title: title.clone(),
+                                                                    sha1,
+                                                                };
+                                                                export_mode.export_file(&export_options, &run, &source, entry)
+                                                            })
+                                                            .collect::<Result<Vec<bool>, AzureError>>()
+                                                            .and_then(|written| {
+                                                                journal.as_ref().map_or(Ok(()), |journal| journal.record_track(index, sha1))
+                                                                    .map(|_| ThreadStatus::Continue((index, !written.contains(&true))))
                                                             })
-                                                            .collect::<Result<(), AzureError>>()
-                                                            .map(|_| ThreadStatus::Continue(index))
                                                     })
                                                     .unwrap_or_else(|err| ThreadStatus::Error(format!("Failed to decode SCD: {}, reason: {:?}", f_name, err), index))
                                             })
@@ -242,13 +335,17 @@ pub fn process(azure_opts: AzureOptions,
                                         let mut threads_completed = 0usize;
                                         let mut files_completed = 0usize;
                                         let mut files_errored = 0usize;
+                                        let mut exported = Vec::new();
+                                        let mut pause_tracker = PauseTracker::new(azure_opts.thread_count);
                                         'thread_recv: for received in recv {
+                                            notify_pause(callbacks, pause_tracker.update(&received), work.len(), files_completed);
                                             match received {
-                                                ThreadStatus::Continue(index) => {
+                                                ThreadStatus::Continue((index, is_skip)) => {
                                                     files_completed += 1;
+                                                    exported.push(index);
                                                     callbacks.process_progress(AzureProcessProgress {
                                                         total_operations_count: work.len(),
-                                                        is_skip: false,
+                                                        is_skip,
                                                         current_operation: index,
                                                         operations_progress: files_completed
                                                     })
@@ -272,14 +369,37 @@ pub fn process(azure_opts: AzureOptions,
                                                         reason
                                                     });
                                                 },
+                                                ThreadStatus::Paused | ThreadStatus::Resumed => {},
                                             } }
                                         callbacks.process_complete(AzureProcessComplete {
                                             operations_completed: files_completed,
                                             operations_errored: files_errored
                                         });
-                                        Ok(())
+                                        Ok(exported)
+                                    })
+                                    .and_then(|exported| {
+                                        if bgm_opts.overwrite != OverwritePolicy::IfSourceChanged {
+                                            return Ok(exported);
+                                        }
+                                        let mut source_record = source_record;
+                                        insert_tracks(&mut source_record, collects.iter()
+                                            .filter(|t_mf| exported.contains(&t_mf.index))
+                                            .cloned(), by_path);
+                                        ::serde_json::to_vec_pretty(&source_record)
+                                            .map_err(|_| AzureError::ErrorExporting("Serializing source record"))
+                                            .and_then(|out| {
+                                                write_atomic(&source_record_path, &out)
+                                                    .map_err(|_| AzureError::ErrorExporting("Writing source record"))
+                                            })
+                                            .map(|_| exported)
+                                    })
+                                    .and_then(|_| {
+                                        if azure_opts.cancel.is_cancelled() {
+                                            Err(AzureError::Cancelled)
+                                        } else {
+                                            Ok(())
+                                        }
                                     })
-                                    .map_err(|o| AzureError::FFXIVError(o))
 
                             })
 
@@ -302,4 +422,162 @@ pub fn process(azure_opts: AzureOptions,
 //    let a = async_processor(1, ffxiv, Vec::new(), |data| {
 //        ThreadStatus::Continue(3usize)
 //    });
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod general_processor_tests {
+    use std::{env, fs};
+    use std::cell::{Cell, RefCell};
+    use ::{AzureOptions, BGMOptions, OverwritePolicy, process_all, process_one};
+    use ::source::{DirectorySource, MemorySource};
+    use ::callbacks::*;
+    use ::exporting::{ExportMode, ExportOptions, PathTemplate, WorkOrder};
+    use ::fixtures::{self, FixtureEntry};
+
+    #[test]
+    fn resume_keeps_colliding_paths() {
+        let dir = env::temp_dir().join(format!("azureost-resume-{}", ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut source = MemorySource::new();
+        source.insert_row(1, "music/ffxiv/BGM.scd");
+        source.insert_row(2, "music/ex1/BGM.scd");
+        source.insert_file("music/ffxiv/BGM.scd", fixtures::scd(&[FixtureEntry::new(2, 22050, 1000).msadpcm()]).unwrap());
+        source.insert_file("music/ex1/BGM.scd", fixtures::scd(&[FixtureEntry::new(2, 22050, 2000).msadpcm()]).unwrap());
+        // both tracks map to `BGM.wav`
+        let options = ExportOptions {
+            path_template: PathTemplate::parse("{scd_name}{ext}").unwrap(),
+            journal: Some(dir.join("journal.jsonl")),
+            ..ExportOptions::default()
+        };
+        let bgm_opts = || BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), options.clone(), OverwritePolicy::Always).unwrap();
+
+        // the first run is interrupted after the first track
+        process_one(&1usize, AzureOptions::from_source(source.clone(), 1), bgm_opts(), &NoOpCallback).unwrap();
+        process_all(AzureOptions::from_source(source, 1), bgm_opts(), &NoOpCallback).unwrap();
+
+        let data_len = |file: &str| {
+            let wav = fs::read(dir.join(file)).unwrap();
+            wav[40..44].iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as usize)
+        };
+        assert_eq!(data_len("BGM.wav"), 1000 * 4);
+        assert_eq!(data_len("BGM_2.wav"), 2000 * 4);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn resume_keeps_claims_across_threads() {
+        let dir = env::temp_dir().join(format!("azureost-resume-threads-{}", ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut source = MemorySource::new();
+        for &(index, folder, frames) in &[(1, "ffxiv", 1000), (2, "ex1", 2000), (3, "ex2", 3000)] {
+            let path = format!("music/{}/BGM.scd", folder);
+            source.insert_row(index, path.as_str());
+            source.insert_file(&path, fixtures::scd(&[FixtureEntry::new(2, 22050, frames).msadpcm()]).unwrap());
+        }
+        let options = ExportOptions {
+            path_template: PathTemplate::parse("{scd_name}{ext}").unwrap(),
+            journal: Some(dir.join("journal.jsonl")),
+            ..ExportOptions::default()
+        };
+        let bgm_opts = || BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), options.clone(), OverwritePolicy::Always).unwrap();
+
+        // the interrupted run finished the last track first, which took the plain name
+        process_one(&3usize, AzureOptions::from_source(source.clone(), 1), bgm_opts(), &NoOpCallback).unwrap();
+        process_all(AzureOptions::from_source(source, 3), bgm_opts(), &NoOpCallback).unwrap();
+
+        let data_len = |file: &str| {
+            let wav = fs::read(dir.join(file)).unwrap();
+            wav[40..44].iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as usize)
+        };
+        assert_eq!(data_len("BGM.wav"), 3000 * 4);
+        let mut resumed = vec![data_len("BGM_2.wav"), data_len("BGM_3.wav")];
+        resumed.sort();
+        assert_eq!(resumed, vec![1000 * 4, 2000 * 4]);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn skips_unreadable_tracks() {
+        let dir = env::temp_dir().join(format!("azureost-unreadable-{}", ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut source = MemorySource::new();
+        source.insert_row(1, "music/ffxiv/BGM_Missing.scd");
+        source.insert_row(2, "music/ffxiv/BGM.scd");
+        source.insert_file("music/ffxiv/BGM.scd", fixtures::scd(&[FixtureEntry::new(2, 22050, 1000).msadpcm()]).unwrap());
+        // sorting by size hashes every track first
+        let options = ExportOptions { work_order: WorkOrder::LargestFirst, ..ExportOptions::default() };
+        let bgm_opts = BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), options, OverwritePolicy::Always).unwrap();
+        process_all(AzureOptions::from_source(source, 2), bgm_opts, &NoOpCallback).unwrap();
+
+        assert!(dir.join("ffxiv/BGM.wav").is_file());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn exports_loose_files() {
+        let root = env::temp_dir().join(format!("azureost-loose-{}", ::std::process::id()));
+        let dir = root.join("export");
+        fs::remove_dir_all(&root).ok();
+        let scd = fixtures::scd(&[FixtureEntry::new(2, 22050, 1000).msadpcm()]).unwrap();
+        for file in &["Loose.SCD", "a/Track.scd", "b/Track.scd", "music/ffxiv/BGM.scd"] {
+            let path = root.join("scds").join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, &scd).unwrap();
+        }
+        let azure_opts = AzureOptions::from_source(DirectorySource::new(root.join("scds")).unwrap(), 2);
+        let bgm_opts = BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), ExportOptions::default(), OverwritePolicy::Always).unwrap();
+        process_all(azure_opts, bgm_opts, &NoOpCallback).unwrap();
+
+        // only a leading music folder is dropped, so loose folders keep tracks apart
+        for file in &["Loose.wav", "a/Track.wav", "b/Track.wav", "ffxiv/BGM.wav"] {
+            assert!(dir.join(file).is_file(), "{} was not exported", file);
+        }
+        fs::remove_dir_all(&root).ok();
+    }
+
+    /// Records the order in which tracks finish exporting.
+    #[derive(Default)]
+    struct ExportOrder {
+        exporting: Cell<bool>,
+        order: RefCell<Vec<usize>>,
+    }
+
+    impl AzureCallbacks for ExportOrder {
+        fn pre_phase(&self, phase: AzureProcessPhase) {
+            self.exporting.set(phase == AzureProcessPhase::Exporting);
+        }
+        fn post_phase(&self, _: AzureProcessPhase) {}
+        fn process_begin(&self, _: AzureProcessBegin) {}
+        fn process_progress(&self, info: AzureProcessProgress) {
+            if self.exporting.get() {
+                self.order.borrow_mut().push(info.current_operation);
+            }
+        }
+        fn process_nonfatal_error(&self, _: AzureProcessNonfatalError) {}
+        fn process_complete(&self, _: AzureProcessComplete) {}
+    }
+
+    #[test]
+    fn largest_first_order() {
+        let dir = env::temp_dir().join(format!("azureost-order-{}", ::std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut source = MemorySource::new();
+        for &(index, frames) in &[(1, 1000), (2, 3000), (3, 2000)] {
+            let path = format!("music/ffxiv/BGM_{}.scd", index);
+            source.insert_row(index, path.as_str());
+            source.insert_file(&path, fixtures::scd(&[FixtureEntry::new(2, 22050, frames).msadpcm()]).unwrap());
+        }
+        let export_order = |work_order| {
+            let options = ExportOptions { work_order, ..ExportOptions::default() };
+            let bgm_opts = BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), options, OverwritePolicy::Always).unwrap();
+            let callbacks = ExportOrder::default();
+            // a single thread finishes the tracks in the order it is handed them
+            process_all(AzureOptions::from_source(source.clone(), 1), bgm_opts, &callbacks).unwrap();
+            callbacks.order.into_inner()
+        };
+
+        assert_eq!(export_order(WorkOrder::Listed), vec![1, 2, 3]);
+        assert_eq!(export_order(WorkOrder::LargestFirst), vec![2, 3, 1]);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use ::sha1::Digest;
+use serde::Serialize;
+use serde::Deserialize;
+use ::errors::AzureError;
+
+/// One line of the journal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+enum JournalRecord {
+    /// Every output of one layer of an SCD entry was written, to the files it claimed.
+    Layer { index: usize, entry: usize, layer: usize, sha1: Digest, #[serde(default)] files: Vec<String> },
+    /// Every entry of the track was exported.
+    Track { index: usize, sha1: Digest },
+}
+
+/// An append-only record of the work finished during an export, one JSON record per line, used to
+/// resume an interrupted run. Work is only considered finished if the SCD hash recorded with it
+/// matches the current one. A line cut short by a crash is ignored when the journal is read.
+///
+/// Layers record the files they were written to, so a resumed run can hold those paths for them
+/// before any other track claims one.
+///
+/// The journal does not record the export settings, so it should only be reused with the same
+/// export mode and options.
+#[derive(Debug)]
+pub struct Journal {
+    file: Mutex<File>,
+    layers: HashMap<(usize, usize, usize), (Digest, Vec<String>)>,
+    tracks: HashMap<usize, Digest>,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, reading the work recorded by earlier runs. The file is created
+    /// if it does not exist.
+    pub fn open(path: &Path) -> Result<Journal, AzureError> {
+        let open_err = |_| AzureError::ErrorExporting("Opening journal");
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path).map_err(open_err)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).map_err(open_err)?;
+        let mut layers = HashMap::new();
+        let mut tracks = HashMap::new();
+        String::from_utf8_lossy(&contents).lines()
+            .filter_map(|line| ::serde_json::from_str::<JournalRecord>(line).ok())
+            .for_each(|record| match record {
+                JournalRecord::Layer { index, entry, layer, sha1, files } => { layers.insert((index, entry, layer), (sha1, files)); },
+                JournalRecord::Track { index, sha1 } => { tracks.insert(index, sha1); },
+            });
+        // end a line cut short by a crash so the next record starts on its own line
+        if contents.last().map_or(false, |last| *last != b'\n') {
+            file.write_all(b"\n").map_err(open_err)?;
+        }
+        Ok(Journal { file: Mutex::new(file), layers, tracks })
+    }
+
+    /// Whether the whole track was exported from an SCD with the given hash.
+    pub fn is_track_done(&self, index: usize, sha1: &Digest) -> bool {
+        self.tracks.get(&index).map_or(false, |recorded| recorded == sha1)
+    }
+
+    /// Whether the layer was exported from an SCD with the given hash.
+    pub fn is_layer_done(&self, index: usize, entry: usize, layer: usize, sha1: &Digest) -> bool {
+        self.layers.get(&(index, entry, layer)).map_or(false, |recorded| recorded.0 == *sha1)
+    }
+
+    /// The files written by every layer recorded as done, for tracks whose SCD hash in `sha1s`
+    /// still matches.
+    pub fn done_files(&self, sha1s: &HashMap<usize, Digest>) -> Vec<String> {
+        self.layers.iter()
+            .filter(|((index, _, _), (sha1, _))| sha1s.get(index) == Some(sha1))
+            .flat_map(|(_, (_, files))| files.iter().cloned())
+            .collect()
+    }
+
+    pub fn record_layer(&self, index: usize, entry: usize, layer: usize, sha1: Digest, files: Vec<String>) -> Result<(), AzureError> {
+        self.append(&JournalRecord::Layer { index, entry, layer, sha1, files })
+    }
+
+    pub fn record_track(&self, index: usize, sha1: Digest) -> Result<(), AzureError> {
+        self.append(&JournalRecord::Track { index, sha1 })
+    }
+
+    fn append(&self, record: &JournalRecord) -> Result<(), AzureError> {
+        let mut line = ::serde_json::to_vec(record)
+            .map_err(|_| AzureError::ErrorExporting("Serializing journal record"))?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)
+            .and_then(|_| file.sync_data())
+            .map_err(|_| AzureError::ErrorExporting("Writing journal"))
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use ::sha1::Sha1;
+
+    #[test]
+    fn resumes_recorded_work() {
+        let path = env::temp_dir().join(format!("azureost-journal-{}.jsonl", ::std::process::id()));
+        fs::remove_file(&path).ok();
+        let sha1 = Sha1::from("scd").digest();
+        {
+            let journal = Journal::open(&path).unwrap();
+            journal.record_layer(4, 0, 1, sha1, vec!["ffxiv/BGM.ogg".into()]).unwrap();
+            journal.record_layer(6, 0, 1, sha1, vec!["ffxiv/BGM_2.ogg".into()]).unwrap();
+            journal.record_track(4, sha1).unwrap();
+        }
+        // a record cut short by a crash
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"Layer\":{\"ind").unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.is_layer_done(4, 0, 1, &sha1));
+        assert!(!journal.is_layer_done(4, 0, 2, &sha1));
+        assert!(journal.is_track_done(4, &sha1));
+        assert!(!journal.is_track_done(4, &Sha1::from("changed").digest()));
+        let sha1s = vec![(4, sha1), (6, Sha1::from("changed").digest())].into_iter().collect();
+        assert_eq!(journal.done_files(&sha1s), vec!["ffxiv/BGM.ogg".to_string()]);
+        journal.record_track(5, sha1).unwrap();
+        assert!(Journal::open(&path).unwrap().is_track_done(5, &sha1));
+        fs::remove_file(&path).unwrap();
+    }
+}
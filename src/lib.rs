@@ -3,26 +3,40 @@ extern crate threadpool;
 extern crate fallible_iterator;
 extern crate sha1;
 extern crate serde;
+extern crate ogg;
 
 extern crate serde_json;
 
 use std::path::PathBuf;
 use std::fs::{OpenOptions, File};
+use std::sync::Arc;
 
 mod process_all;
 mod general_processor;
 mod async_data_processor;
+mod atomic_write;
+mod journal;
+mod control;
 mod exporting;
+mod scd;
 
 
 pub mod errors;
 pub mod manifest;
 pub mod selector;
 pub mod callbacks;
+pub mod source;
+#[cfg(any(test, feature="fixtures"))]
+pub mod fixtures;
 
 pub use process_all::{process_one, process_all};
 pub use callbacks::AzureCallbacks;
-pub use exporting::ExportMode;
+pub use source::{AudioSource, DirectorySource, MemorySource};
+pub use scd::{ScdEntry, MsAdpcm};
+pub use sqpack_blue::sheet::ex::SheetLanguage;
+pub use journal::Journal;
+pub use control::{CancellationToken, PauseHandle};
+pub use exporting::{ExportMode, ExportSource, ExportSidecar, ExportOptions, LoopPolicy, MAX_LOOP_DURATION, FadeSettings, FadeCurve, FadeLength, OutputNaming, PathTemplate, CollisionPolicy, WorkOrder, ChannelLayout, OutputPaths, ExportRun, VorbisPreset, VorbisSettings, LameBitrateMode, LameSettings};
 
 use errors::AzureError;
 use sqpack_blue::FFXIV;
@@ -31,19 +45,41 @@ use sqpack_blue::FFXIV;
 /// the manifest file to compare against, and the export mode and path to use. Should be
 /// instantiated using its ::new() function, which validates its arguments and returns a result.
 pub struct BGMOptions {
-    save_file: Option<File>,
+    save_file: Option<PathBuf>,
     compare_file: Option<manifest::ManifestFile>,
     export_mode: Option<ExportMode>,
+    export_options: ExportOptions,
+    overwrite: OverwritePolicy,
 }
 
-/// Holds data pertaining to the operation of the process, including the sqpack_blue FFXIV structure
-/// and the number of threads to use during expensive procedures. Should be instantiated using its
-/// ::new() function, which validates its arguments and returns a result.
+/// Controls whether an export may replace files that already exist in the export directory, such
+/// as those left by an interrupted run or edited by the user. Tracks whose files were all kept are
+/// reported with `AzureProcessProgress::is_skip` set.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverwritePolicy {
+    /// Always write, replacing existing files.
+    Always,
+    /// Never replace existing files; only missing ones are written.
+    Never,
+    /// Replace the existing files of a track only when its SCD has changed since it was last
+    /// exported to the directory. Source hashes are recorded in `.azureost-sources.json` inside
+    /// the export directory, and tracks missing from it count as changed.
+    IfSourceChanged,
+}
+
+/// Holds data pertaining to the operation of the process, including the source of the BGM sheet and
+/// SCD files, and the number of threads to use during expensive procedures. Should be instantiated
+/// using its ::new() function, which validates its arguments and returns a result, or with
+/// ::from_source() to read from another `AudioSource`.
 
 #[derive(Clone)]
 pub struct AzureOptions {
-    ffxiv: FFXIV,
-    thread_count: usize
+    source: Arc<AudioSource>,
+    /// Kept for `bgm_csv`, when the source is an FFXIV install
+    ffxiv: Option<FFXIV>,
+    thread_count: usize,
+    cancel: CancellationToken,
+    pause: PauseHandle,
 }
 
 impl BGMOptions {
@@ -52,7 +88,8 @@ impl BGMOptions {
     /// # Parameters:
     /// * `save_file` - An Option referencing the location of the to be generated manifest file. This
     /// should not already exist - for safety purposes this program will not truncate manifest files.
-    /// If you would like to skip the generation of a manifest, use the `None` variant here.
+    /// The file is created empty here, and replaced in one step once the manifest has been fully
+    /// written. If you would like to skip the generation of a manifest, use the `None` variant here.
     /// * `compare_file` - An Option referencing the location of an existing manifest file to compare
     /// against. If you would like to skip comparisons and operate on all possible values, without
     /// regard for changes, use the `None` variant here.
@@ -60,14 +97,24 @@ impl BGMOptions {
     /// No checking is done here. If the directory does not exist it will be made during the export
     /// process. If the directory does exist, the outputted files will be placed inside. If the path
     /// conflicts with a file, errors will be thrown during the export process (but will not panic!.)
-    /// If you would like to skip exporting, use the `None` variant here.
+    /// If you would like to skip exporting, use the `None` variant here. The encoder settings
+    /// carried by the mode are validated here, returning `AzureError::InvalidExportSettings`.
+    /// * `export_options` - Options applied to every export, such as the loop policy. Use
+    /// `ExportOptions::default()` for the standard behaviour. These are validated as well.
+    /// * `overwrite` - Whether existing files in the export directory may be replaced. Use
+    /// `OverwritePolicy::Always` to replace them unconditionally.
     pub fn new(save_file: Option<PathBuf>,
                compare_file: Option<PathBuf>,
-               export_mode: Option<ExportMode>) -> Result<BGMOptions, AzureError> {
+               export_mode: Option<ExportMode>,
+               export_options: ExportOptions,
+               overwrite: OverwritePolicy) -> Result<BGMOptions, AzureError> {
+        // validate before touching the filesystem, so bad settings leave no empty save file behind
+        export_mode.as_ref().map_or(Ok(()), |mode| mode.validate())?;
+        export_options.validate()?;
         save_file.map_or(Ok(None), |f_str| {
-            OpenOptions::new().write(true).create_new(true).open(f_str).map_err(|_| {
+            OpenOptions::new().write(true).create_new(true).open(&f_str).map_err(|_| {
                 AzureError::UnableToCreateSaveFile
-            }).map(|f| Some(f))
+            }).map(|_| Some(f_str))
         }).and_then(|save_file| {
             compare_file.map_or(Ok(None), |f_str| {
                 OpenOptions::new().read(true).open(f_str).map_err(|_| {
@@ -82,7 +129,9 @@ impl BGMOptions {
             Ok(BGMOptions {
                 save_file,
                 compare_file,
-                export_mode
+                export_mode,
+                export_options,
+                overwrite,
             })
         })
     }
@@ -100,9 +149,44 @@ impl AzureOptions {
         Ok(ffxiv_path.as_path())
             .and_then(|ff| FFXIV::new(ff).ok_or(AzureError::NoFFXIV))
             .and_then(|ffxiv| {
-                Ok(AzureOptions{ ffxiv, thread_count })
+                Ok(AzureOptions{
+                    source: Arc::new(ffxiv.clone()),
+                    ffxiv: Some(ffxiv),
+                    thread_count,
+                    cancel: CancellationToken::new(),
+                    pause: PauseHandle::new(),
+                })
             })
     }
+
+    /// Creates an instance of AzureOptions that reads the BGM sheet and SCD files from `source`,
+    /// such as a `MemorySource`, rather than an FFXIV installation.
+    /// # Arguments
+    /// * `source` - the `AudioSource` to process.
+    /// * `thread_count` - the number of threads to use for expensive operations, such as hashing,
+    /// exporting, etc.
+    pub fn from_source<S: AudioSource + 'static>(source: S, thread_count: usize) -> AzureOptions {
+        AzureOptions {
+            source: Arc::new(source),
+            ffxiv: None,
+            thread_count,
+            cancel: CancellationToken::new(),
+            pause: PauseHandle::new(),
+        }
+    }
+
+    /// Returns a token that cancels any process run with these options (or a clone of them).
+    /// Take it before passing the options to `process_all` or `process_one`, which then return
+    /// `AzureError::Cancelled` once the files in progress are finished.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Returns a handle that pauses and resumes any process run with these options (or a clone of
+    /// them). Like the cancellation token, take it before starting the process.
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.pause.clone()
+    }
 }
 
 /// Writes FFXIV's BGM datasheet to a specified file
@@ -115,17 +199,19 @@ impl AzureOptions {
 /// * `Err(AzureError::UnableToCreateSaveFile)` - Indicates that the requested output file was
 /// unable to be opened for writing or created.
 /// * `Err(AzureError::FFXIVError)` - Indicates there was an error when parsing the BGM sheet internally.
+/// * `Err(AzureError::NoFFXIV)` - Indicates the options were not created from an FFXIV installation.
 pub fn bgm_csv(azure_opts: AzureOptions, output: PathBuf) -> Result<(), AzureError> {
     use sqpack_blue::sheet::write_csv;
+    let ffxiv = azure_opts.ffxiv.ok_or(AzureError::NoFFXIV)?;
     OpenOptions::new().create(true).truncate(true).write(true).open(output)
         .map_err(|_| AzureError::UnableToCreateSaveFile)
         .and_then(|save_file| {
-            azure_opts.ffxiv.get_sheet_index().map_err(|o| AzureError::FFXIVError(o))
+            ffxiv.get_sheet_index().map_err(|o| AzureError::FFXIVError(o))
                 .map(|sheet_index| (save_file, sheet_index))
         })
         .and_then(|(save_file, sheet_index)| {
             use ::sqpack_blue::sheet::ex::SheetLanguage;
-            azure_opts.ffxiv.get_sheet(&String::from("bgm"), SheetLanguage::None, &sheet_index)
+            ffxiv.get_sheet(&String::from("bgm"), SheetLanguage::None, &sheet_index)
                 .map_err(|o| AzureError::FFXIVError(o))
                 .map(|sheet| (save_file, sheet))
         })
@@ -166,15 +252,60 @@ mod tests {
     #[test]
     fn it_works() {
         use super::*;
-        use std::path::Path;
-        use std::fs;
-        fs::remove_file("output.json").ok();
-        let azopt = AzureOptions::new(Path::new(&std::env::var("FFXIV_SQPACK_PATH").unwrap()).to_path_buf(),
-                                      4usize
-        ).unwrap();
-        let bgmopt = BGMOptions::new(Some(Path::new("output.json").to_path_buf()), None, None).unwrap();
+        use std::{env, fs};
+        use sha1::Sha1;
+        let manifest_path = env::temp_dir().join(format!("azureost-manifest-{}.json", std::process::id()));
+        fs::remove_file(&manifest_path).ok();
+        let mut source = MemorySource::new();
+        source.insert_row(0, "");
+        source.insert_row(1, "music/ffxiv/BGM_Field_01.scd");
+        source.insert_row(2, "music/ffxiv/BGM_Field_02.scd");
+        source.insert_file("music/ffxiv/BGM_Field_01.scd", vec![1, 2, 3]);
+        source.insert_file("music/ffxiv/bgm_field_02.scd", vec![4, 5]);
+        let azopt = AzureOptions::from_source(source, 4usize);
+        let bgmopt = BGMOptions::new(Some(manifest_path.clone()), None, None, ExportOptions::default(), OverwritePolicy::Always).unwrap();
         process_all(azopt, bgmopt, &MyCB{}).unwrap();
 //        process_one(&639usize, azopt, bgmopt, &MyCB{}).unwrap();
+
+        let manifest: manifest::ManifestFile = serde_json::from_slice(&fs::read(&manifest_path).unwrap()).unwrap();
+        fs::remove_file(&manifest_path).ok();
+        assert_eq!(manifest.files.keys().cloned().collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(manifest.files[&2].sha1, Sha1::from(&[4u8, 5]).digest());
+    }
+
+    #[test]
+    fn exports_fixtures() {
+        use super::*;
+        use std::{env, fs};
+        let dir = env::temp_dir().join(format!("azureost-fixtures-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut source = MemorySource::new();
+        source.insert_row(1, "music/ffxiv/BGM_Test.scd");
+        source.insert_file("music/ffxiv/BGM_Test.scd", fixtures::scd(&[
+            fixtures::FixtureEntry::new(4, 44100, 60000).looped(10000, 50000),
+        ]).unwrap());
+        let azopt = AzureOptions::from_source(source, 2);
+        let bgmopt = BGMOptions::new(None, None, Some(ExportMode::WAV(dir.clone())), ExportOptions::default(), OverwritePolicy::Always).unwrap();
+        process_all(azopt, bgmopt, &MyCB{}).unwrap();
+
+        // one stereo file per layer, unlooped, with the loop points in a smpl chunk
+        for layer in 1..3 {
+            let wav = fs::read(dir.join(format!("ffxiv/BGM_Test_layer{}.wav", layer))).unwrap();
+            let read_u32 = |at: usize| wav[at..at + 4].iter().rev().fold(0, |acc, byte| (acc << 8) | *byte as usize);
+            assert_eq!((wav[22], read_u32(40)), (2, 60000 * 4));
+            assert_eq!((read_u32(44 + 60000 * 4 + 52), read_u32(44 + 60000 * 4 + 56)), (10000, 49999));
+        }
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn validates_before_creating_save_file() {
+        use super::*;
+        use std::env;
+        let manifest_path = env::temp_dir().join(format!("azureost-invalid-{}.json", std::process::id()));
+        let options = ExportOptions { loop_policy: LoopPolicy::Count(0), ..ExportOptions::default() };
+        assert!(BGMOptions::new(Some(manifest_path.clone()), None, None, options, OverwritePolicy::Always).is_err());
+        assert!(!manifest_path.exists());
     }
 
 }
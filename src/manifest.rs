@@ -8,6 +8,10 @@ pub struct ManifestFile {
     // This is purely aesthetic, and only serves to output prettier manifest files.
     // TODO: provide feature to use HashMap for slight improvement in speed
     pub files: BTreeMap<usize, TrackManifest>,
+    /// Tracks read from loose SCD files rather than the BGM sheet, keyed by their path relative to
+    /// the directory they were read from. Their `index` is only their position in that run.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub loose_files: BTreeMap<String, TrackManifest>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,7 +45,8 @@ mod manifest_tests {
                     sha1: Sha1::from("13234234").digest(),
                     name: "bgm_neko_nyaaa.scd".into()
                 }
-            ].into_iter().map(|mf| (mf.index.clone(), mf)).collect()
+            ].into_iter().map(|mf| (mf.index.clone(), mf)).collect(),
+            loose_files: BTreeMap::new(),
         };
         let sha_str = ::serde_json::to_string(&manifest).unwrap();
         println!("{}", ::serde_json::to_string(&manifest).unwrap());
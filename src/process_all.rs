@@ -1,5 +1,4 @@
 use ::{BGMOptions, AzureOptions, AzureError, AzureCallbacks};
-use ::sqpack_blue::sheet::ex::SheetLanguage;
 use ::selector::Selector;
 
 /// Process all files in the BGM sheet using the provided AzureOptions and BGMOptions. Callbacks are
@@ -9,25 +8,13 @@ use ::selector::Selector;
 /// * `bgm_opts` - The BGM options to use
 /// * `callbacks` - A reference to an AzureCallbacks implementation. If no specific callback
 /// functionality is desired `azure_ost_core::callbacks::NoOpCallback` may be used.
+///
+/// Returns `AzureError::Cancelled` when stopped through `AzureOptions::cancellation_token`.
 pub fn process_all(azure_opts: AzureOptions, bgm_opts: BGMOptions, callbacks: &AzureCallbacks) -> Result<(), AzureError>
 {
-    let ffxiv = azure_opts.ffxiv.clone();
-
-    Ok(ffxiv)
-        // get the Sheet index
-        .and_then(|ffxiv| {
-            ffxiv.get_sheet_index().map_err(|e| e.into())
-                .map(|a| (ffxiv, a))
-        })
-        // Get the BGM Sheet using the sheet index
-        .and_then(|(ffxiv, sheet_index)| {
-            ffxiv.get_sheet(&String::from("bgm"),
-                            SheetLanguage::None, &sheet_index)
-                .map_err(|e| e.into())
-                .map(|a| (ffxiv, a))
-        })
-        .map(|(_, sheet)| {
-            sheet.rows.keys().cloned().collect::<Vec<usize>>()
+    azure_opts.source.bgm_paths()
+        .map(|paths| {
+            paths.keys().cloned().collect::<Vec<usize>>()
         })
         .and_then(|process_indices| {
             ::general_processor::process(azure_opts, bgm_opts, process_indices, callbacks)
@@ -43,15 +30,11 @@ pub fn process_all(azure_opts: AzureOptions, bgm_opts: BGMOptions, callbacks: &A
 /// * `bgm_opts` - The BGM options to use
 /// * `callbacks` - A reference to an AzureCallbacks implementation. If no specific callback
 /// functionality is desired `azure_ost_core::callbacks::NoOpCallback` may be used.
+///
+/// Returns `AzureError::Cancelled` when stopped through `AzureOptions::cancellation_token`.
 pub fn process_one(selected: &Selector, azure_opts: AzureOptions,
                        bgm_opts: BGMOptions, ac: &AzureCallbacks) -> Result<(), AzureError> {
-    let ffxiv = azure_opts.ffxiv.clone();
-
-    Ok(ffxiv)
-        // get the Sheet index
-        .and_then(|ffxiv| {
-            selected.select_azure_ost(&ffxiv)
-        })
+    selected.select_azure_ost(&*azure_opts.source)
         .and_then(|index| {
             ::general_processor::process(azure_opts, bgm_opts, vec![index], ac)
         })
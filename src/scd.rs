@@ -0,0 +1,316 @@
+//! A reader for SCD files, the sound containers FFXIV stores its music in, for sources that do not
+//! go through sqpack_blue.
+
+use ::errors::AzureError;
+
+const SCD_MAGIC: &[u8; 8] = b"SEDBSSCF";
+const CODEC_OGG: u32 = 0x06;
+const CODEC_MSADPCM: u32 = 0x0C;
+/// Placeholder entries without audio
+const CODEC_NONE: u32 = 0xFFFF_FFFF;
+const ENTRY_HEADER_LEN: usize = 0x20;
+const OGG_HEADER_LEN: usize = 0x20;
+/// The `wFormatTag` of Microsoft ADPCM in a WAVEFORMATEX
+const WAVE_FORMAT_ADPCM: usize = 0x02;
+/// The table the whole Ogg stream of version 3 entries is XOR-ed with
+const OGG_XOR_TABLE: [u8; 256] = [
+    0x3A, 0x32, 0x32, 0x32, 0x03, 0x7E, 0x12, 0xF7, 0xB2, 0xE2, 0xA2, 0x67, 0x32, 0x32, 0x22, 0x32,
+    0x32, 0x52, 0x16, 0x1B, 0x3C, 0xA1, 0x54, 0x7B, 0x1B, 0x97, 0xA6, 0x93, 0x1A, 0x4B, 0xAA, 0xA6,
+    0x7A, 0x7B, 0x1B, 0x97, 0xA6, 0xF7, 0x02, 0xBB, 0xAA, 0xA6, 0xBB, 0xF7, 0x2A, 0x51, 0xBE, 0x03,
+    0xF4, 0x2A, 0x51, 0xBE, 0x03, 0xF4, 0x2A, 0x51, 0xBE, 0x12, 0x06, 0x56, 0x27, 0x32, 0x32, 0x36,
+    0x32, 0xB2, 0x1A, 0x3B, 0xBC, 0x91, 0xD4, 0x7B, 0x58, 0xFC, 0x0B, 0x55, 0x2A, 0x15, 0xBC, 0x40,
+    0x92, 0x0B, 0x5B, 0x7C, 0x0A, 0x95, 0x12, 0x35, 0xB8, 0x63, 0xD2, 0x0B, 0x3B, 0xF0, 0xC7, 0x14,
+    0x51, 0x5C, 0x94, 0x86, 0x94, 0x59, 0x5C, 0xFC, 0x1B, 0x17, 0x3A, 0x3F, 0x6B, 0x37, 0x32, 0x32,
+    0x30, 0x32, 0x72, 0x7A, 0x13, 0xB7, 0x26, 0x60, 0x7A, 0x13, 0xB7, 0x26, 0x50, 0xBA, 0x13, 0xB4,
+    0x2A, 0x50, 0xBA, 0x13, 0xB5, 0x2E, 0x40, 0xFA, 0x13, 0x95, 0xAE, 0x40, 0x38, 0x18, 0x9A, 0x92,
+    0xB0, 0x38, 0x00, 0xFA, 0x12, 0xB1, 0x7E, 0x00, 0xDB, 0x96, 0xA1, 0x7C, 0x08, 0xDB, 0x9A, 0x91,
+    0xBC, 0x08, 0xD8, 0x1A, 0x86, 0xE2, 0x70, 0x39, 0x1F, 0x86, 0xE0, 0x78, 0x7E, 0x03, 0xE7, 0x64,
+    0x51, 0x9C, 0x8F, 0x34, 0x6F, 0x4E, 0x41, 0xFC, 0x0B, 0xD5, 0xAE, 0x41, 0xFC, 0x0B, 0xD5, 0xAE,
+    0x41, 0xFC, 0x3B, 0x70, 0x71, 0x64, 0x33, 0x32, 0x12, 0x32, 0x32, 0x36, 0x70, 0x34, 0x2B, 0x56,
+    0x22, 0x70, 0x3A, 0x13, 0xB7, 0x26, 0x60, 0xBA, 0x1B, 0x94, 0xAA, 0x40, 0x38, 0x00, 0xFA, 0xB2,
+    0xE2, 0xA2, 0x67, 0x32, 0x32, 0x12, 0x32, 0xB2, 0x32, 0x32, 0x32, 0x32, 0x75, 0xA3, 0x26, 0x7B,
+    0x83, 0x26, 0xF9, 0x83, 0x2E, 0xFF, 0xE3, 0x16, 0x7D, 0xC0, 0x1E, 0x63, 0x21, 0x07, 0xE3, 0x01,
+];
+
+/// The audio of one SCD entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScdEntry {
+    /// A complete Ogg Vorbis stream, with any loop points in its `LoopStart` and `LoopEnd` comments.
+    Ogg(Vec<u8>),
+    /// Microsoft ADPCM audio, used by older and some system tracks.
+    MsAdpcm(MsAdpcm),
+}
+
+/// Microsoft ADPCM audio and the parameters needed to decode it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MsAdpcm {
+    pub channels: usize,
+    pub rate: u64,
+    /// The length in bytes of a block, which holds every channel
+    pub block_align: usize,
+    /// The predictor coefficient pairs a block header can select
+    pub coefficients: Vec<(i16, i16)>,
+    /// The loop start and end from the entry header, converted to frames
+    pub loop_points: Option<(usize, usize)>,
+    pub data: Vec<u8>,
+}
+
+impl MsAdpcm {
+    /// The length of a block header: a predictor index, delta and two samples for each channel.
+    pub fn block_header_len(&self) -> usize {
+        7 * self.channels
+    }
+
+    /// The number of frames a full block decodes to: the two samples of its header, then two
+    /// nibbles per byte.
+    pub fn frames_per_block(&self) -> usize {
+        frames_in_block(self.block_align, self.channels)
+    }
+
+    /// The number of frames the audio decodes to.
+    pub fn frames(&self) -> usize {
+        bytes_to_frames(self.data.len(), self.block_align, self.channels)
+    }
+}
+
+fn frames_in_block(block_len: usize, channels: usize) -> usize {
+    if channels == 0 || block_len < 7 * channels {
+        0
+    } else {
+        (block_len - 7 * channels) * 2 / channels + 2
+    }
+}
+
+/// Converts a byte offset into MSADPCM audio to a frame offset, as SCD entry headers store the
+/// loop points of MSADPCM entries in bytes.
+pub fn bytes_to_frames(bytes: usize, block_align: usize, channels: usize) -> usize {
+    if block_align == 0 {
+        return 0;
+    }
+    (bytes / block_align) * frames_in_block(block_align, channels) + frames_in_block(bytes % block_align, channels)
+}
+
+/// Reads the integers of an SCD file in its byte order.
+struct Fields<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&'a [u8], AzureError> {
+        offset.checked_add(len)
+            .and_then(|end| self.data.get(offset..end))
+            .ok_or(AzureError::ErrorDecoding)
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<usize, AzureError> {
+        let bytes = self.bytes(offset, len)?;
+        let fold = |acc: usize, byte: &u8| (acc << 8) | *byte as usize;
+        Ok(if self.big_endian { bytes.iter().fold(0, fold) } else { bytes.iter().rev().fold(0, fold) })
+    }
+
+    fn u8(&self, offset: usize) -> Result<u8, AzureError> {
+        self.bytes(offset, 1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&self, offset: usize) -> Result<usize, AzureError> {
+        self.uint(offset, 2)
+    }
+
+    fn i16(&self, offset: usize) -> Result<i16, AzureError> {
+        self.uint(offset, 2).map(|value| value as u16 as i16)
+    }
+
+    fn u32(&self, offset: usize) -> Result<usize, AzureError> {
+        self.uint(offset, 4)
+    }
+}
+
+/// Splits an SCD file into the audio of its entries, in the order they are stored. Ogg Vorbis
+/// entries are restored, whether their headers are XOR-ed with a single byte (Ogg header versions
+/// 0 to 2) or their whole stream with a table (version 3), and MSADPCM entries are returned
+/// undecoded with their loop points. Other codecs return `AzureError::ErrorDecoding`.
+pub fn entries(data: &[u8]) -> Result<Vec<ScdEntry>, AzureError> {
+    if !data.starts_with(SCD_MAGIC) {
+        return Err(AzureError::ErrorDecoding);
+    }
+    let fields = Fields { data, big_endian: data.get(0x0C).map_or(false, |flag| *flag != 0) };
+    let tables = fields.u16(0x0E)?;
+    let entry_count = fields.u16(tables + 0x04)?;
+    let entry_table = fields.u32(tables + 0x0C)?;
+
+    let mut entries = Vec::with_capacity(entry_count);
+    for entry in 0..entry_count {
+        let header = fields.u32(entry_table + entry * 4)?;
+        let codec = fields.u32(header + 0x0C)? as u32;
+        entries.push(match codec {
+            CODEC_NONE => continue,
+            CODEC_OGG => ScdEntry::Ogg(ogg_entry(&fields, header)?),
+            CODEC_MSADPCM => ScdEntry::MsAdpcm(msadpcm_entry(&fields, header)?),
+            _ => return Err(AzureError::ErrorDecoding),
+        });
+    }
+    Ok(entries)
+}
+
+/// The offset of an entry's codec data, which follows any auxiliary chunks such as loop markers.
+fn codec_data(fields: &Fields, header: usize) -> Result<usize, AzureError> {
+    let aux_chunks = fields.u32(header + 0x1C)?;
+    let codec_data = header + ENTRY_HEADER_LEN;
+    if aux_chunks > 0 {
+        Ok(codec_data + fields.u32(codec_data + 0x04)?)
+    } else {
+        Ok(codec_data)
+    }
+}
+
+/// The audio of an entry, which follows its codec data.
+fn audio<'a>(fields: &Fields<'a>, header: usize) -> Result<&'a [u8], AzureError> {
+    let stream_len = fields.u32(header)?;
+    let extra_len = fields.u32(header + 0x18)?;
+    fields.bytes(header + ENTRY_HEADER_LEN + extra_len, stream_len)
+}
+
+fn ogg_entry(fields: &Fields, header: usize) -> Result<Vec<u8>, AzureError> {
+    let codec_data = codec_data(fields, header)?;
+    let version = fields.u8(codec_data)?;
+    let xor = fields.u8(codec_data + 0x02)?;
+    let seek_table_len = fields.u32(codec_data + 0x10)?;
+    let vorbis_header_len = fields.u32(codec_data + 0x14)?;
+    if version > 3 {
+        return Err(AzureError::ErrorDecoding);
+    }
+
+    let vorbis_header = codec_data + OGG_HEADER_LEN + seek_table_len;
+    let mut ogg = fields.bytes(vorbis_header, vorbis_header_len)?.to_vec();
+    if version < 3 && xor != 0 {
+        ogg.iter_mut().for_each(|byte| *byte ^= xor);
+    }
+    ogg.extend_from_slice(audio(fields, header)?);
+    if version == 3 {
+        xor_with_table(&mut ogg, fields.u32(header)?);
+    }
+    Ok(ogg)
+}
+
+/// Undoes, or applies, the obfuscation of version 3 Ogg entries: every byte of the stream is
+/// XOR-ed with the table, read from an offset taken from the length of the audio, and with that
+/// length itself.
+fn xor_with_table(ogg: &mut [u8], stream_len: usize) {
+    let (length_xor, table_offset) = ((stream_len & 0x7F) as u8, stream_len & 0x3F);
+    ogg.iter_mut().enumerate().for_each(|(i, byte)| {
+        *byte ^= OGG_XOR_TABLE[(table_offset + i) & 0xFF] ^ length_xor;
+    });
+}
+
+/// Reads an MSADPCM entry, whose codec data is a WAVEFORMATEX with the ADPCM extension.
+fn msadpcm_entry(fields: &Fields, header: usize) -> Result<MsAdpcm, AzureError> {
+    let channels = fields.u32(header + 0x04)?;
+    let rate = fields.u32(header + 0x08)? as u64;
+    let (loop_start, loop_end) = (fields.u32(header + 0x10)?, fields.u32(header + 0x14)?);
+    let format = codec_data(fields, header)?;
+    let block_align = fields.u16(format + 0x0C)?;
+    let coefficient_count = fields.u16(format + 0x14)?;
+    if fields.u16(format)? != WAVE_FORMAT_ADPCM || channels == 0 || rate == 0 || block_align < 7 * channels || coefficient_count == 0 {
+        return Err(AzureError::ErrorDecoding);
+    }
+    let coefficients = (0..coefficient_count)
+        .map(|i| Ok((fields.i16(format + 0x16 + i * 4)?, fields.i16(format + 0x18 + i * 4)?)))
+        .collect::<Result<Vec<_>, AzureError>>()?;
+
+    // the loop points are byte offsets into the audio
+    let to_frames = |bytes| bytes_to_frames(bytes, block_align, channels);
+    let loop_points = if loop_start < loop_end { Some((to_frames(loop_start), to_frames(loop_end))) } else { None };
+    Ok(MsAdpcm { channels, rate, block_align, coefficients, loop_points, data: audio(fields, header)?.to_vec() })
+}
+
+#[cfg(test)]
+mod scd_tests {
+    use super::*;
+
+    fn push_u32(out: &mut Vec<u8>, value: usize) {
+        out.extend_from_slice(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    /// An SCD with one Ogg entry, its Vorbis header XOR-ed with `xor`.
+    fn scd(vorbis_header: &[u8], audio: &[u8], xor: u8) -> Vec<u8> {
+        let mut out = SCD_MAGIC.to_vec();
+        push_u32(&mut out, 3);
+        out.extend_from_slice(&[0, 4, 0x30, 0]);
+        push_u32(&mut out, 0);
+        out.resize(0x30, 0);
+        // tables: one entry, its offset stored at 0x50
+        out.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0]);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, 0x50);
+        out.resize(0x50, 0);
+        push_u32(&mut out, 0x60);
+        out.resize(0x60, 0);
+
+        let seek_table = [0u8; 4];
+        push_u32(&mut out, audio.len());
+        push_u32(&mut out, 2);
+        push_u32(&mut out, 44100);
+        push_u32(&mut out, CODEC_OGG as usize);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, OGG_HEADER_LEN + seek_table.len() + vorbis_header.len());
+        push_u32(&mut out, 0);
+        out.extend_from_slice(&[2, 0x20, xor, 0]);
+        out.extend_from_slice(&[0; 12]);
+        push_u32(&mut out, seek_table.len());
+        push_u32(&mut out, vorbis_header.len());
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&seek_table);
+        out.extend(vorbis_header.iter().map(|byte| byte ^ xor));
+        out.extend_from_slice(audio);
+        out
+    }
+
+    #[test]
+    fn restores_ogg_stream() {
+        assert_eq!(entries(&scd(b"OggS header", b"audio", 0x73)).unwrap(), vec![ScdEntry::Ogg(b"OggS headeraudio".to_vec())]);
+        assert_eq!(entries(&scd(b"OggS", b"", 0)).unwrap(), vec![ScdEntry::Ogg(b"OggS".to_vec())]);
+    }
+
+    #[test]
+    fn restores_table_obfuscated_ogg() {
+        let mut obfuscated = scd(b"OggS header", b"audio", 0);
+        let ogg_start = obfuscated.len() - 16;
+        xor_with_table(&mut obfuscated[ogg_start..], 5);
+        // the Ogg header version, at the start of the codec data
+        obfuscated[0x80] = 3;
+        assert_ne!(&obfuscated[ogg_start..ogg_start + 4], b"OggS");
+        assert_eq!(entries(&obfuscated).unwrap(), vec![ScdEntry::Ogg(b"OggS headeraudio".to_vec())]);
+    }
+
+    #[test]
+    fn reads_fixtures() {
+        use ::fixtures::{FixtureEntry, wrap};
+        let fixtures = vec![
+            FixtureEntry::new(2, 44100, 500).entry().unwrap(),
+            FixtureEntry::new(4, 48000, 300).looped(100, 200).entry().unwrap(),
+            // loop points that fall on encoded frames survive the conversion to bytes and back
+            FixtureEntry::new(2, 44100, 1500).looped(200, 1200).msadpcm().entry().unwrap(),
+        ];
+        assert_eq!(entries(&wrap(&fixtures, 0xA5)).unwrap(), fixtures);
+    }
+
+    #[test]
+    fn adpcm_loop_offsets() {
+        // stereo blocks of 0x100 bytes hold 2 header frames and 242 encoded ones
+        assert_eq!(bytes_to_frames(0x100 * 3, 0x100, 2), 244 * 3);
+        assert_eq!(bytes_to_frames(0x100 + 14 + 10, 0x100, 2), 244 + 12);
+        assert_eq!(bytes_to_frames(0x100 + 3, 0x100, 2), 244);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(entries(b"OggS").is_err());
+        let truncated = scd(b"OggS header", b"audio", 0);
+        assert!(entries(&truncated[..truncated.len() - 1]).is_err());
+        let mut zero_rate = ::fixtures::FixtureEntry::new(2, 44100, 500).msadpcm_audio();
+        zero_rate.rate = 0;
+        assert!(entries(&::fixtures::wrap(&[ScdEntry::MsAdpcm(zero_rate)], 0)).is_err());
+    }
+}
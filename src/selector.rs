@@ -1,34 +1,26 @@
 use ::AzureError;
-use ::sqpack_blue::FFXIV;
-use ::sqpack_blue::sheet::ex::SheetLanguage;
+use ::source::AudioSource;
 
 pub trait Selector: Send + Sync {
-    fn select_azure_ost(&self, ffxiv: &FFXIV) -> Result<usize, AzureError>;
+    fn select_azure_ost(&self, source: &AudioSource) -> Result<usize, AzureError>;
 }
 
 impl Selector for usize {
     #[inline]
-    fn select_azure_ost(&self, _: &FFXIV) -> Result<usize, AzureError> {
+    fn select_azure_ost(&self, _: &AudioSource) -> Result<usize, AzureError> {
         Ok(*self)
     }
 }
 
 impl Selector for String {
-    fn select_azure_ost(&self, ffxiv: &FFXIV) -> Result<usize, AzureError> {
-        ffxiv.get_sheet_index()
-            .and_then(|sheet_index| ffxiv.get_sheet(&String::from("bgm"), SheetLanguage::None, &sheet_index))
+    fn select_azure_ost(&self, source: &AudioSource) -> Result<usize, AzureError> {
+        source.bgm_paths()
             .map_err(|_| AzureError::UnableToSelect)
-            .and_then(|sheet| {
-                sheet.rows.iter().enumerate().find(|(_, row)| {
-                    (row.1).read_cell_data::<String>(0)
-                        .map(|title| self.eq_ignore_ascii_case(title.as_str()))
-                        .unwrap_or(false)
-                })
-                    .and_then(|row| {
-                        Some(row.0)
-                    })
+            .and_then(|paths| {
+                paths.iter().find(|(_, path)| self.eq_ignore_ascii_case(path.as_str()))
+                    .map(|(index, _)| *index)
                     .ok_or(AzureError::UnableToSelect)
             })
 
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,296 @@
+//! Where the BGM sheet and the SCD files it names are read from. Processing only reaches the game
+//! through `AudioSource`, which is implemented for sqpack_blue's `FFXIV`, for `DirectorySource` to
+//! process loose SCD files, and for `MemorySource` to run without a game install.
+
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use ::errors::AzureError;
+use ::scd::{self, ScdEntry};
+use ::sqpack_blue::{FFXIV, FFXIVError, Index};
+use ::sqpack_blue::sheet::ex::SheetLanguage;
+
+/// Lists the rows of the BGM sheet and reads the SCD files they name.
+pub trait AudioSource: Send + Sync {
+    /// Returns the SCD path of every row in the BGM sheet, keyed by row index. Rows without a
+    /// track have an empty path.
+    fn bgm_paths(&self) -> Result<BTreeMap<usize, String>, AzureError>;
+
+    /// Checks a path from the BGM sheet before any work is started. Paths that pass may still
+    /// fail to read, which is then reported for that track alone.
+    fn is_valid_path(&self, _path: &str) -> bool {
+        true
+    }
+
+    /// Whether manifests identify tracks by their SCD path rather than their BGM index, for
+    /// sources whose indexes are not stable between runs.
+    fn keys_by_path(&self) -> bool {
+        false
+    }
+
+    /// Creates a reader on a worker thread, which uses it for every file it takes. Readers may keep
+    /// state between files, such as loaded indexes.
+    fn reader(&self) -> Box<ScdReader>;
+
+    /// Splits an SCD file into the audio of its entries, in the order they are stored.
+    fn decode_scd(&self, data: Vec<u8>) -> Result<Vec<ScdEntry>, AzureError>;
+
+    /// Maps lowercased SCD paths to their Orchestrion titles in the given language. Sources without
+    /// Orchestrion data return no titles, so tracks keep their SCD names.
+    fn orchestrion_titles(&self, _language: SheetLanguage) -> Result<HashMap<String, String>, AzureError> {
+        Ok(HashMap::new())
+    }
+}
+
+/// Reads SCD files on behalf of one worker thread.
+pub trait ScdReader {
+    fn read_scd(&mut self, path: &str) -> Result<Vec<u8>, AzureError>;
+}
+
+impl AudioSource for FFXIV {
+    fn bgm_paths(&self) -> Result<BTreeMap<usize, String>, AzureError> {
+        let sheet_index = self.get_sheet_index()?;
+        let sheet = self.get_sheet(&String::from("bgm"), SheetLanguage::None, &sheet_index)?;
+        sheet.rows.iter()
+            .map(|(index, row)| {
+                row.read_cell_data::<String>(0)
+                    .map(|path| (*index, path))
+                    .map_err(|e| FFXIVError::SheetError(e).into())
+            })
+            .collect()
+    }
+
+    fn is_valid_path(&self, path: &str) -> bool {
+        self.get_exfile(&path.to_string()).is_ok()
+    }
+
+    fn reader(&self) -> Box<ScdReader> {
+        Box::new(SqpackReader { ffxiv: self.clone(), indexes: HashMap::new() })
+    }
+
+    /// Reads the SCD with the crate's own reader, which also handles MSADPCM entries, and falls
+    /// back to sqpack_blue for files it cannot read.
+    fn decode_scd(&self, data: Vec<u8>) -> Result<Vec<ScdEntry>, AzureError> {
+        if let Ok(entries) = scd::entries(&data) {
+            return Ok(entries);
+        }
+        self.decode_sound(data)
+            .map_err(|_| AzureError::ErrorDecoding)
+            .map(|sound| sound.entries.iter().map(|entry| ScdEntry::Ogg(entry.decoded().clone())).collect())
+    }
+
+    /// Joins the Orchestrion and OrchestrionPath sheets on their row index. When several
+    /// Orchestrion entries share an SCD the first is used.
+    fn orchestrion_titles(&self, language: SheetLanguage) -> Result<HashMap<String, String>, AzureError> {
+        let sheet_index = self.get_sheet_index()?;
+        let paths = self.get_sheet(&String::from("orchestrionpath"), SheetLanguage::None, &sheet_index)?;
+        let names = self.get_sheet(&String::from("orchestrion"), language, &sheet_index)?;
+        let mut titles = HashMap::new();
+        for (index, path_row) in paths.rows.iter() {
+            let name = match names.rows.get(index) {
+                Some(name_row) => name_row.read_cell_data::<String>(0).map_err(|e| FFXIVError::SheetError(e))?,
+                None => continue,
+            };
+            let path = path_row.read_cell_data::<String>(0).map_err(|e| FFXIVError::SheetError(e))?;
+            if !path.is_empty() && !name.is_empty() {
+                titles.entry(path.to_lowercase()).or_insert(name);
+            }
+        }
+        Ok(titles)
+    }
+}
+
+/// Reads files from the sqpack, keeping the indexes it has loaded.
+struct SqpackReader {
+    ffxiv: FFXIV,
+    indexes: HashMap<String, Index>,
+}
+
+impl ScdReader for SqpackReader {
+    fn read_scd(&mut self, path: &str) -> Result<Vec<u8>, AzureError> {
+        let ffxiv = &self.ffxiv;
+        let exf = ffxiv.get_exfile(&path.to_string())?;
+        let index = match self.indexes.entry(exf.get_sqpack_base_file_name()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(ffxiv.get_index(&exf)?),
+        };
+        Ok(ffxiv.get_raw_data_with_index(&exf, index)?)
+    }
+}
+
+/// An `AudioSource` holding a BGM sheet and its SCD files in memory, for tests and tools that run
+/// without a game install. Paths are matched case-insensitively, as in the sqpack. SCD files are
+/// split by the crate's own reader, which handles MSADPCM entries and Ogg Vorbis entries whose
+/// header is unobfuscated or XOR-ed with a single byte.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    rows: BTreeMap<usize, String>,
+    files: Arc<HashMap<String, Vec<u8>>>,
+    titles: HashMap<String, String>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    /// Adds a row to the BGM sheet naming `path`, replacing any row with the same index.
+    pub fn insert_row<S: Into<String>>(&mut self, index: usize, path: S) {
+        self.rows.insert(index, path.into());
+    }
+
+    /// Adds the SCD file stored at `path`.
+    pub fn insert_file(&mut self, path: &str, data: Vec<u8>) {
+        Arc::make_mut(&mut self.files).insert(path.to_lowercase(), data);
+    }
+
+    /// Gives the SCD at `path` an Orchestrion title, used in every language.
+    pub fn insert_title<S: Into<String>>(&mut self, path: &str, title: S) {
+        self.titles.insert(path.to_lowercase(), title.into());
+    }
+}
+
+impl AudioSource for MemorySource {
+    fn bgm_paths(&self) -> Result<BTreeMap<usize, String>, AzureError> {
+        Ok(self.rows.clone())
+    }
+
+    fn reader(&self) -> Box<ScdReader> {
+        Box::new(MemoryReader { files: self.files.clone() })
+    }
+
+    fn decode_scd(&self, data: Vec<u8>) -> Result<Vec<ScdEntry>, AzureError> {
+        scd::entries(&data)
+    }
+
+    fn orchestrion_titles(&self, _language: SheetLanguage) -> Result<HashMap<String, String>, AzureError> {
+        Ok(self.titles.clone())
+    }
+}
+
+struct MemoryReader {
+    files: Arc<HashMap<String, Vec<u8>>>,
+}
+
+impl ScdReader for MemoryReader {
+    fn read_scd(&mut self, path: &str) -> Result<Vec<u8>, AzureError> {
+        self.files.get(&path.to_lowercase())
+            .cloned()
+            .ok_or_else(|| AzureError::UnableToReadSource(path.to_string()))
+    }
+}
+
+/// An `AudioSource` reading loose SCD files, such as those extracted by other tools. The files
+/// stand in for the rows of the BGM sheet, ordered by their path relative to the root directory,
+/// and manifests identify them by that path. Files extracted with their game folders
+/// (`music/ffxiv/...`) export to the same paths as they would from the game. SCD files are split
+/// as by `MemorySource`.
+#[derive(Debug, Clone)]
+pub struct DirectorySource {
+    root: PathBuf,
+    paths: Vec<String>,
+}
+
+impl DirectorySource {
+    /// Finds every `.scd` file in `root` and its subdirectories.
+    pub fn new(root: PathBuf) -> Result<DirectorySource, AzureError> {
+        let mut files = Vec::new();
+        find_scd_files(&root, &mut files)?;
+        DirectorySource::with_files(root, files)
+    }
+
+    /// Uses the given SCD files. Relative paths are taken from `root`, and absolute paths must lie
+    /// within it.
+    pub fn with_files(root: PathBuf, files: Vec<PathBuf>) -> Result<DirectorySource, AzureError> {
+        let mut paths = files.iter()
+            .map(|file| relative_path(&root, &root.join(file)))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        paths.dedup();
+        Ok(DirectorySource { root, paths })
+    }
+}
+
+fn find_scd_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), AzureError> {
+    let unreadable = || AzureError::UnableToReadSource(dir.display().to_string());
+    for entry in fs::read_dir(dir).map_err(|_| unreadable())? {
+        let path = entry.map_err(|_| unreadable())?.path();
+        if path.is_dir() {
+            find_scd_files(&path, files)?;
+        } else if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("scd")) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The path of `file` within `root`, separated by `/` as in the sqpack.
+fn relative_path(root: &Path, file: &Path) -> Result<String, AzureError> {
+    file.strip_prefix(root).ok()
+        .and_then(|relative| {
+            relative.components()
+                .map(|component| match component {
+                    Component::Normal(name) => name.to_str(),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+        })
+        .map(|components| components.join("/"))
+        .ok_or_else(|| AzureError::UnableToReadSource(file.display().to_string()))
+}
+
+impl AudioSource for DirectorySource {
+    fn bgm_paths(&self) -> Result<BTreeMap<usize, String>, AzureError> {
+        Ok(self.paths.iter().cloned().enumerate().collect())
+    }
+
+    fn keys_by_path(&self) -> bool {
+        true
+    }
+
+    fn reader(&self) -> Box<ScdReader> {
+        Box::new(DirectoryReader { root: self.root.clone() })
+    }
+
+    fn decode_scd(&self, data: Vec<u8>) -> Result<Vec<ScdEntry>, AzureError> {
+        scd::entries(&data)
+    }
+}
+
+struct DirectoryReader {
+    root: PathBuf,
+}
+
+impl ScdReader for DirectoryReader {
+    fn read_scd(&mut self, path: &str) -> Result<Vec<u8>, AzureError> {
+        fs::read(self.root.join(path)).map_err(|_| AzureError::UnableToReadSource(path.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod source_tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn directory_files() {
+        let root = env::temp_dir().join(format!("azureost-source-{}", ::std::process::id()));
+        fs::create_dir_all(root.join("music/ffxiv")).unwrap();
+        fs::write(root.join("music/ffxiv/BGM_Field_01.scd"), [1]).unwrap();
+        fs::write(root.join("Loose.SCD"), [2]).unwrap();
+        fs::write(root.join("notes.txt"), [3]).unwrap();
+
+        let source = DirectorySource::new(root.clone()).unwrap();
+        let paths = source.bgm_paths().unwrap();
+        assert_eq!(paths.values().collect::<Vec<_>>(), vec!["Loose.SCD", "music/ffxiv/BGM_Field_01.scd"]);
+        assert_eq!(source.reader().read_scd(&paths[&1]).unwrap(), vec![1]);
+
+        let listed = DirectorySource::with_files(root.clone(), vec![root.join("Loose.SCD"), PathBuf::from("Loose.SCD")]).unwrap();
+        assert_eq!(listed.bgm_paths().unwrap().len(), 1);
+        assert!(DirectorySource::with_files(root.clone(), vec![PathBuf::from("../elsewhere.scd")]).is_err());
+        fs::remove_dir_all(&root).ok();
+    }
+}
@@ -1,11 +1,45 @@
 extern crate azure_ost_core;
+extern crate serde_json;
 struct MyCB;
 use azure_ost_core::callbacks::*;
 use azure_ost_core::*;
 
+#[test]
+fn it_works() {
+    use std::{env, fs};
+    use azure_ost_core::manifest::ManifestFile;
+    let manifest_path = env::temp_dir().join(format!("azureost-integration-{}.json", std::process::id()));
+    fs::remove_file(&manifest_path).ok();
+    let mut source = MemorySource::new();
+    source.insert_row(0, "music/ffxiv/BGM_Field_01.scd");
+    source.insert_row(7, "music/ffxiv/BGM_Town_01.scd");
+    source.insert_file("music/ffxiv/BGM_Field_01.scd", vec![1]);
+    source.insert_file("music/ffxiv/BGM_Town_01.scd", vec![2]);
+    let azopt = AzureOptions::from_source(source, 2);
+    let bgmopt = BGMOptions::new(Some(manifest_path.clone()), None, None, ExportOptions::default(), OverwritePolicy::Always).unwrap();
+    process_one(&String::from("MUSIC/FFXIV/BGM_TOWN_01.SCD"), azopt, bgmopt, &NoOpCallback).unwrap();
 
+    let manifest: ManifestFile = serde_json::from_slice(&fs::read(&manifest_path).unwrap()).unwrap();
+    fs::remove_file(&manifest_path).ok();
+    assert_eq!(manifest.files.keys().cloned().collect::<Vec<_>>(), vec![7]);
+}
 
 #[test]
-fn it_works() {
+fn loose_files() {
+    use std::{env, fs};
+    use azure_ost_core::manifest::ManifestFile;
+    let root = env::temp_dir().join(format!("azureost-loose-{}", std::process::id()));
+    let manifest_path = env::temp_dir().join(format!("azureost-loose-{}.json", std::process::id()));
+    fs::create_dir_all(root.join("music/ex1")).unwrap();
+    fs::write(root.join("music/ex1/BGM_EX1_Town_01.scd"), [1]).unwrap();
+    fs::remove_file(&manifest_path).ok();
+    let azopt = AzureOptions::from_source(DirectorySource::new(root.clone()).unwrap(), 2);
+    let bgmopt = BGMOptions::new(Some(manifest_path.clone()), None, None, ExportOptions::default(), OverwritePolicy::Always).unwrap();
+    process_all(azopt, bgmopt, &NoOpCallback).unwrap();
 
-}
\ No newline at end of file
+    let manifest: ManifestFile = serde_json::from_slice(&fs::read(&manifest_path).unwrap()).unwrap();
+    fs::remove_file(&manifest_path).ok();
+    fs::remove_dir_all(&root).ok();
+    assert!(manifest.files.is_empty());
+    assert_eq!(manifest.loose_files.keys().collect::<Vec<_>>(), vec!["music/ex1/BGM_EX1_Town_01.scd"]);
+}